@@ -0,0 +1,6 @@
+#!/bin/bash
+set -e
+cd "`dirname $0`"
+cargo build --target wasm32-unknown-unknown --release
+mkdir -p ./res
+cp target/wasm32-unknown-unknown/release/*.wasm ./res/
@@ -0,0 +1,66 @@
+use crate::*;
+
+#[near_bindgen]
+impl Contract {
+    /// Caps the rewards paid out by all claims together at `max_emission_per_epoch` per epoch of
+    /// `epoch_duration_sec` seconds, with epochs starting now. A claim over the remaining budget
+    /// is paid up to it, and the rest stays unclaimed for the next epoch. Zero
+    /// `epoch_duration_sec` removes the cap. Can only be called by the owner.
+    pub fn set_emission_cap(&mut self, max_emission_per_epoch: U128, epoch_duration_sec: u64) {
+        self.assert_owner();
+        self.max_emission_per_epoch = max_emission_per_epoch.into();
+        self.epoch_duration_sec = epoch_duration_sec;
+        self.epoch_start_sec = env::block_timestamp() / NANOS_PER_SECOND;
+        self.emitted_this_epoch = 0;
+    }
+
+    /// Returns the rewards that can still be paid out in the current epoch, or `None` if
+    /// emissions aren't capped.
+    pub fn get_emission_budget(&self) -> Option<U128> {
+        self.internal_emission_budget().map(U128)
+    }
+}
+
+impl Contract {
+    /// Start of the epoch the current block is in, in seconds.
+    fn internal_current_epoch_start_sec(&self) -> u64 {
+        let now_sec = env::block_timestamp() / NANOS_PER_SECOND;
+        now_sec - (now_sec - self.epoch_start_sec) % self.epoch_duration_sec
+    }
+
+    /// Rewards that can still be paid out in the current epoch, if emissions are capped.
+    pub(crate) fn internal_emission_budget(&self) -> Option<Balance> {
+        if self.epoch_duration_sec == 0 {
+            return None;
+        }
+        let emitted = if self.internal_current_epoch_start_sec() == self.epoch_start_sec {
+            self.emitted_this_epoch
+        } else {
+            0
+        };
+        Some(self.max_emission_per_epoch.saturating_sub(emitted))
+    }
+
+    /// Counts `amount` towards the emissions of the current epoch, rolling over to a new epoch
+    /// first if the previous one ended.
+    pub(crate) fn internal_record_emission(&mut self, amount: Balance) {
+        if self.epoch_duration_sec == 0 {
+            return;
+        }
+        let epoch_start_sec = self.internal_current_epoch_start_sec();
+        if epoch_start_sec != self.epoch_start_sec {
+            self.epoch_start_sec = epoch_start_sec;
+            self.emitted_this_epoch = 0;
+        }
+        self.emitted_this_epoch += amount;
+    }
+
+    /// Gives `amount` of a failed payout back to the budget, unless its epoch already ended.
+    pub(crate) fn internal_revert_emission(&mut self, amount: Balance) {
+        if self.epoch_duration_sec > 0
+            && self.internal_current_epoch_start_sec() == self.epoch_start_sec
+        {
+            self.emitted_this_epoch = self.emitted_this_epoch.saturating_sub(amount);
+        }
+    }
+}
@@ -0,0 +1,81 @@
+//! Standard for NFT staking events, logged in the NEP-297 format:
+//!
+//! `EVENT_JSON:{"standard":"nft_staking","version":"1.0.0","event":"stake","data":[...]}`
+use near_contract_standards::non_fungible_token::TokenId;
+use near_sdk::json_types::U128;
+use near_sdk::serde::Serialize;
+use near_sdk::{env, AccountId};
+
+const STANDARD: &str = "nft_staking";
+const VERSION: &str = "1.0.0";
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeData<'a> {
+    pub account_id: &'a AccountId,
+    pub token_id: &'a TokenId,
+    pub nft_contract: &'a AccountId,
+}
+
+/// Unstake of a token, logged once the NFT was returned to the staker. `amount` is the reward
+/// credited to the staker, or to the reward recipient of the stake, including the compounded
+/// bonus weight.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct UnstakeData<'a> {
+    pub account_id: &'a AccountId,
+    pub token_id: &'a TokenId,
+    pub nft_contract: &'a AccountId,
+    pub amount: U128,
+}
+
+/// Reward payout to `account_id`, logged once the transfer succeeded. `nft_contract` and
+/// `token_id` are omitted for claims across all stakes.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct ClaimData<'a> {
+    pub account_id: &'a AccountId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nft_contract: Option<&'a AccountId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<&'a TokenId>,
+    pub amount: U128,
+}
+
+/// Emergency unstake of a token, logged once the NFT was returned to the staker. `amount` is the
+/// forfeited reward, including the compounded bonus weight.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct EmergencyUnstakeData<'a> {
+    pub account_id: &'a AccountId,
+    pub token_id: &'a TokenId,
+    pub nft_contract: &'a AccountId,
+    pub amount: U128,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+#[serde(tag = "event", content = "data")]
+#[serde(rename_all = "snake_case")]
+pub enum StakingEvent<'a> {
+    Stake([StakeData<'a>; 1]),
+    Unstake([UnstakeData<'a>; 1]),
+    Claim([ClaimData<'a>; 1]),
+    EmergencyUnstake([EmergencyUnstakeData<'a>; 1]),
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+struct EventLog<'a> {
+    standard: &'static str,
+    version: &'static str,
+    #[serde(flatten)]
+    event: StakingEvent<'a>,
+}
+
+impl StakingEvent<'_> {
+    pub fn emit(self) {
+        let log = EventLog { standard: STANDARD, version: VERSION, event: self };
+        env::log_str(&format!("EVENT_JSON:{}", near_sdk::serde_json::to_string(&log).unwrap()));
+    }
+}
@@ -0,0 +1,3649 @@
+/*!
+NFT staking contract.
+NOTES:
+  - Users stake by calling `nft_transfer_call` on an NFT contract with this contract as the
+    receiver. The NFT contract then calls `nft_on_transfer`, and the stake is recorded for the
+    previous owner of the token.
+  - Only NFT contracts on the allowed list that also have an enabled reward pool are accepted.
+    For any other collection `nft_on_transfer` returns `true`, which makes the NFT contract
+    return the token. Removing a collection from the list only stops new stakes. A collection can
+    also be restricted to a set of eligible token ids.
+  - Alternatively, `stake_with_approval` stakes a token the caller approved this contract for,
+    leaving the NFT in their wallet. Claims, compounds and unstakes of such a stake first check
+    with `nft_token` that the staker still holds the token and the approval, and cancel the stake
+    without rewards if not.
+  - Stakes are keyed by NFT contract and token id, so tokens with the same id in different
+    collections are staked independently.
+  - Rewards accrue per whole second of staking at the `reward_rate_per_second` of the
+    collection's pool, in the smallest unit of the reward token, scaled by the token's rarity
+    multiplier. A pool can halve its rate at a fixed interval, in which case rewards are summed
+    per halving period. On unstake they are credited to the staker's unclaimed reward balance.
+    The reward token's `decimals` are fetched at initialization for display.
+  - `compound` turns the pending rewards of a stake into bonus weight instead of paying them
+    out. Bonus weight earns `COMPOUND_APR_BPS` per year on top of the pool rate, and is credited
+    back when the token is unstaked, slashed like the other rewards if the stake is still locked.
+    An emergency unstake, or a cancelled stake by approval, forfeits it with the other rewards.
+  - A pool can have a cliff: stakes earn nothing for the first `cliff_duration_sec` seconds, and
+    only the time after the cliff is rewarded. Each stake keeps the cliff the pool had when it
+    started, so changing it doesn't affect running stakes.
+  - A pool can have a set bonus that raises the rate of all stakes of an account in the
+    collection while it has at least `set_bonus_threshold` tokens of it staked. The bonus is
+    determined by the count at settlement.
+  - Divisions of the accrued rewards are floored, and their remainders are carried on the stake
+    into the next settlement, so frequent settlements don't lose rewards to truncation.
+  - A stake can be locked by passing `{"lock_duration_sec": <seconds>}` as the transfer `msg`.
+    Unstaking before the lock ends still returns the NFT, but slashes the rewards settled at
+    unstake by `early_unstake_penalty_bps`. Until then, the rewards of the stake can't be claimed
+    or compounded, and relocking only extends the lock, so they all stay subject to the penalty.
+  - Rewards are paid from a reserve funded by transferring reward tokens to this contract with
+    `ft_transfer_call`. Transfers of any other token are refunded.
+  - The staker can delegate the rewards of a stake to a reward recipient. All rewards the stake
+    settles, including those of relocks and unstakes, are credited to the recipient. Unstaking
+    still returns the NFT to the staker.
+  - Stakers pay for the storage of their stakes with a NEP-145 `storage_deposit` made before
+    staking, and every account for that of its reward balances. Staking without enough available
+    deposit fails, which makes the NFT contract return the token. Storage released by an unstake
+    is returned to the deposit.
+  - Stakes, unstakes and claims are logged as NEP-297 events, see `events`.
+  - Claims pay the unclaimed balance out with `ft_transfer` on the reward token, up to the
+    reserve and the emission budget of the current epoch, if the owner capped emissions. If the
+    transfer fails, the amount is credited back and returned to the reserve.
+*/
+use near_contract_standards::fungible_token::metadata::FungibleTokenMetadata;
+use near_contract_standards::fungible_token::receiver::FungibleTokenReceiver;
+use near_contract_standards::non_fungible_token::core::NonFungibleTokenReceiver;
+use near_contract_standards::non_fungible_token::{Token, TokenId};
+use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
+use near_sdk::collections::{LookupMap, UnorderedMap, UnorderedSet};
+use near_sdk::json_types::U128;
+use near_sdk::serde::{Deserialize, Serialize};
+use near_sdk::serde_json;
+use near_sdk::{
+    env, ext_contract, log, near_bindgen, AccountId, Balance, Gas, PanicOnDefault, Promise,
+    PromiseOrValue, PromiseResult, StorageUsage,
+};
+
+use crate::events::{ClaimData, EmergencyUnstakeData, StakeData, StakingEvent, UnstakeData};
+pub use crate::pool::{Pool, PoolView};
+pub use crate::storage::StorageAccount;
+
+mod emission;
+pub mod events;
+mod pool;
+mod rewards;
+mod storage;
+
+const ONE_YOCTO: Balance = 1;
+const GAS_FOR_NFT_TRANSFER: Gas = Gas(15_000_000_000_000);
+const GAS_FOR_RESOLVE_UNSTAKE: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_FT_TRANSFER: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_RESOLVE_CLAIM: Gas = Gas(20_000_000_000_000);
+const GAS_FOR_NFT_TOKEN: Gas = Gas(10_000_000_000_000);
+const GAS_FOR_FT_METADATA: Gas = Gas(5_000_000_000_000);
+const GAS_FOR_RESOLVE_METADATA: Gas = Gas(5_000_000_000_000);
+/// Covers settling a stake by approval and, for claims, the reward transfer and its callback.
+const GAS_FOR_RESOLVE_APPROVAL: Gas = Gas(40_000_000_000_000);
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+const BPS_DENOMINATOR: u32 = 10_000;
+const MIN_MULTIPLIER_BPS: u32 = 10_000;
+const MAX_MULTIPLIER_BPS: u32 = 100_000;
+/// Yearly rewards earned by compounded bonus weight, in basis points of the weight.
+const COMPOUND_APR_BPS: u32 = 1_000;
+const SECONDS_PER_YEAR: u64 = 365 * 24 * 60 * 60;
+const DEFAULT_PAGE_LIMIT: u64 = 50;
+const MAX_PAGE_LIMIT: u64 = 100;
+/// Maximum number of stakes settled by one `claim_all_rewards` call.
+const MAX_CLAIM_ALL_STAKES: u64 = 100;
+const DEFAULT_MAX_STAKES_PER_ACCOUNT: u32 = 100;
+
+#[ext_contract(ext_nft)]
+pub trait NonFungibleToken {
+    fn nft_transfer(
+        &mut self,
+        receiver_id: AccountId,
+        token_id: TokenId,
+        approval_id: Option<u64>,
+        memo: Option<String>,
+    );
+    fn nft_token(&self, token_id: TokenId) -> Option<Token>;
+}
+
+#[ext_contract(ext_ft)]
+pub trait FungibleToken {
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>);
+    fn ft_metadata(&self) -> FungibleTokenMetadata;
+}
+
+#[ext_contract(ext_self)]
+pub trait StakingResolver {
+    fn resolve_unstake(
+        &mut self,
+        token_id: TokenId,
+        stake: Stake,
+        amount: U128,
+        emergency: bool,
+    ) -> bool;
+    fn on_reward_claimed(
+        &mut self,
+        account_id: AccountId,
+        nft_contract: Option<AccountId>,
+        token_id: Option<TokenId>,
+        amount: U128,
+        claimed: Vec<(AccountId, TokenId, U128)>,
+    ) -> U128;
+    fn resolve_approval_stake(
+        &mut self,
+        account_id: AccountId,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        approval_id: u64,
+    );
+    fn resolve_approval_claim(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        amount: Option<U128>,
+    ) -> PromiseOrValue<U128>;
+    fn resolve_approval_unstake(&mut self, nft_contract: AccountId, token_id: TokenId) -> bool;
+    fn resolve_approval_compound(&mut self, nft_contract: AccountId, token_id: TokenId) -> U128;
+    fn on_reward_token_metadata(&mut self);
+}
+
+#[derive(BorshDeserialize, BorshSerialize, Serialize, Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct Stake {
+    pub owner_id: AccountId,
+    /// NFT contract the token was transferred from.
+    pub nft_contract: AccountId,
+    /// Block timestamp of the stake, in nanoseconds.
+    pub staked_at: u64,
+    /// Block timestamp up to which rewards have been settled, in nanoseconds.
+    pub last_update: u64,
+    /// Cliff of the pool when the stake started, see `Pool::cliff_duration_sec`.
+    pub cliff_duration_sec: u64,
+    /// Seconds the pool had been paused for at `staked_at`, see `Pool::paused_sec_at`.
+    pub paused_sec_at_stake: u64,
+    /// Seconds the pool had been paused for at `last_update`.
+    pub paused_sec_at_update: u64,
+    /// Block timestamp before which unstaking is penalized, in nanoseconds.
+    pub unlock_at: Option<u64>,
+    /// Rewards compounded into the stake, which earn `COMPOUND_APR_BPS` on top of the pool rate,
+    /// until they are credited back by an unstake.
+    pub bonus_weight: Balance,
+    /// Rewards accrued up to `last_update` that haven't been taken yet, as the stake was settled
+    /// for a change of its multiplier. They are added to the next settlement.
+    pub accrued: Balance,
+    /// Remainder of the multiplier and set bonus division, carried into the next settlement.
+    pub base_remainder: Balance,
+    /// Remainder of the bonus weight division, carried into the next settlement.
+    pub bonus_remainder: Balance,
+    /// Account claims of the stake are paid to instead of the staker.
+    pub reward_recipient: Option<AccountId>,
+    /// Approval the staker granted this contract, for stakes by approval where the NFT stays in
+    /// the staker's wallet.
+    pub approval_id: Option<u64>,
+    /// Rewards of the stake that have been paid out.
+    pub lifetime_claimed: Balance,
+    /// Rewards of the stake credited to the unclaimed balance of its reward recipient, or of the
+    /// staker, including those of previous recipients, that haven't been paid out yet. Payouts
+    /// made through the stake are attributed to it up to this amount.
+    pub unclaimed: Balance,
+}
+
+impl Stake {
+    /// Account claims of the stake are paid to: its reward recipient, or the staker.
+    pub fn claimant(&self) -> &AccountId {
+        self.reward_recipient.as_ref().unwrap_or(&self.owner_id)
+    }
+
+    /// Whether the stake is locked at `timestamp`, in nanoseconds.
+    pub fn is_locked(&self, timestamp: u64) -> bool {
+        self.unlock_at.map_or(false, |unlock_at| timestamp < unlock_at)
+    }
+}
+
+/// Arguments accepted in the `msg` of `nft_transfer_call`.
+#[derive(Deserialize)]
+#[serde(crate = "near_sdk::serde")]
+struct StakeArgs {
+    lock_duration_sec: Option<u64>,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakeView {
+    pub token_id: TokenId,
+    pub owner_id: AccountId,
+    pub nft_contract: AccountId,
+    pub staked_at: u64,
+    pub unlock_at: Option<u64>,
+    pub bonus_weight: U128,
+    pub pending_rewards: U128,
+    pub reward_recipient: Option<AccountId>,
+    pub approval_id: Option<u64>,
+    pub lifetime_claimed: U128,
+}
+
+/// Totals of the contract, so that clients can size their pages without iterating.
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct StakingStats {
+    pub total_stakes: u64,
+    /// Accounts with at least one stake.
+    pub total_stakers: u64,
+    pub total_pools: u64,
+    pub reward_reserve: U128,
+}
+
+#[near_bindgen]
+#[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
+pub struct Contract {
+    owner_id: AccountId,
+    /// Fungible token contract rewards are paid in.
+    reward_token_id: AccountId,
+    /// `decimals` of the reward token metadata, once fetched from the reward token.
+    reward_token_decimals: Option<u8>,
+    allowed_nft_contracts: UnorderedSet<AccountId>,
+    /// Token ids that can be staked, for collections where only some tokens qualify.
+    eligible_token_ids: LookupMap<AccountId, UnorderedSet<TokenId>>,
+    /// Stakes by NFT contract and token id.
+    stakes: LookupMap<(AccountId, TokenId), Stake>,
+    /// NFT contracts and ids of the staked tokens by staker.
+    staker_tokens: LookupMap<AccountId, UnorderedSet<(AccountId, TokenId)>>,
+    /// Number of tokens staked by an account per NFT contract, for the pool set bonus.
+    collection_stake_counts: LookupMap<(AccountId, AccountId), u32>,
+    /// Number of entries in `stakes`.
+    total_stakes: u64,
+    /// Number of entries in `staker_tokens`.
+    total_stakers: u64,
+    /// Reward pools by NFT contract.
+    pools: UnorderedMap<AccountId, Pool>,
+    /// Rewards that were settled for an account but not paid out yet.
+    unclaimed_rewards: LookupMap<AccountId, Balance>,
+    /// Rewards paid out to an account over all its claims, kept after its stakes are gone.
+    account_lifetime_claimed: LookupMap<AccountId, Balance>,
+    /// Share of the settled rewards forfeited when unstaking before the lock ends.
+    early_unstake_penalty_bps: u32,
+    /// Reward multipliers of rare tokens by NFT contract and token id, in basis points. Other
+    /// tokens earn 1x.
+    multipliers: LookupMap<(AccountId, TokenId), u32>,
+    /// Reward tokens held by the contract that are available for claims.
+    reward_reserve: Balance,
+    /// Smallest unclaimed balance that can be claimed.
+    min_claim_amount: Balance,
+    /// Most tokens an account can have staked at once, which bounds the gas of the methods
+    /// iterating an account's stakes.
+    max_stakes_per_account: u32,
+    /// Most rewards paid out per epoch by all claims together, if `epoch_duration_sec` is set.
+    max_emission_per_epoch: Balance,
+    /// Length of an emission epoch in seconds. Zero means emissions aren't capped.
+    epoch_duration_sec: u64,
+    /// Start of the epoch `emitted_this_epoch` is counted for, in seconds.
+    epoch_start_sec: u64,
+    emitted_this_epoch: Balance,
+    /// Storage deposits of stakers.
+    storage_accounts: LookupMap<AccountId, StorageAccount>,
+    /// Bytes taken by the registration of one account in `storage_accounts`.
+    account_storage_usage: StorageUsage,
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Initializes the contract owned by `owner_id`, paying rewards in `reward_token_id` and
+    /// accepting stakes from the given NFT contracts once they have a pool.
+    #[init]
+    pub fn new(
+        owner_id: AccountId,
+        reward_token_id: AccountId,
+        allowed_nft_contracts: Vec<AccountId>,
+    ) -> Self {
+        assert!(!env::state_exists(), "Already initialized");
+        let mut this = Self {
+            owner_id,
+            reward_token_id,
+            reward_token_decimals: None,
+            allowed_nft_contracts: UnorderedSet::new(b"n".to_vec()),
+            eligible_token_ids: LookupMap::new(b"e".to_vec()),
+            stakes: LookupMap::new(b"s".to_vec()),
+            staker_tokens: LookupMap::new(b"o".to_vec()),
+            collection_stake_counts: LookupMap::new(b"c".to_vec()),
+            total_stakes: 0,
+            total_stakers: 0,
+            pools: UnorderedMap::new(b"p".to_vec()),
+            unclaimed_rewards: LookupMap::new(b"u".to_vec()),
+            account_lifetime_claimed: LookupMap::new(b"l".to_vec()),
+            early_unstake_penalty_bps: 0,
+            multipliers: LookupMap::new(b"x".to_vec()),
+            reward_reserve: 0,
+            min_claim_amount: 0,
+            max_stakes_per_account: DEFAULT_MAX_STAKES_PER_ACCOUNT,
+            max_emission_per_epoch: 0,
+            epoch_duration_sec: 0,
+            epoch_start_sec: 0,
+            emitted_this_epoch: 0,
+            storage_accounts: LookupMap::new(b"d".to_vec()),
+            account_storage_usage: 0,
+        };
+        this.measure_account_storage_usage();
+        for nft_contract in allowed_nft_contracts.iter() {
+            this.allowed_nft_contracts.insert(nft_contract);
+        }
+        // Dropping the promise still schedules it.
+        this.internal_fetch_reward_token_decimals();
+        this
+    }
+
+    /// Fetches the `decimals` of the reward token metadata again, e.g. if the initial fetch
+    /// failed. Can only be called by the owner.
+    pub fn refresh_reward_token_decimals(&mut self) -> Promise {
+        self.assert_owner();
+        self.internal_fetch_reward_token_decimals()
+    }
+
+    /// Returns the `decimals` of the reward token, which all reward amounts, including pool
+    /// rates, are scaled by. Panics if they haven't been fetched yet.
+    pub fn get_reward_token_decimals(&self) -> u8 {
+        self.reward_token_decimals.expect("Reward token decimals haven't been fetched yet")
+    }
+
+    /// Callback of the `ft_metadata` call made by `new` and `refresh_reward_token_decimals`.
+    /// Caches the decimals of the reward token, or keeps the previous value if the call failed.
+    #[private]
+    pub fn on_reward_token_metadata(&mut self) {
+        let metadata = match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice::<FungibleTokenMetadata>(&value).ok()
+            }
+            _ => None,
+        };
+        match metadata {
+            Some(metadata) => self.reward_token_decimals = Some(metadata.decimals),
+            None => log!("Failed to fetch the metadata of @{}", self.reward_token_id),
+        }
+    }
+
+    /// Returns the stake of `token_id` of `nft_contract`, if it is staked.
+    pub fn get_stake(&self, nft_contract: AccountId, token_id: TokenId) -> Option<StakeView> {
+        self.stakes
+            .get(&(nft_contract, token_id.clone()))
+            .map(|stake| self.stake_view(token_id, stake))
+    }
+
+    /// Returns a page of the stakes of `account_id`. `limit` defaults to 50 and is capped at 100.
+    pub fn get_stakes_by_owner(
+        &self,
+        account_id: AccountId,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<StakeView> {
+        let tokens = match self.staker_tokens.get(&account_id) {
+            Some(tokens) => tokens,
+            None => return vec![],
+        };
+        let from_index = from_index.unwrap_or(0);
+        let limit = limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+        tokens
+            .as_vector()
+            .iter()
+            .skip(from_index as usize)
+            .take(limit as usize)
+            .map(|key| {
+                let stake = self.stakes.get(&key).unwrap();
+                self.stake_view(key.1, stake)
+            })
+            .collect()
+    }
+
+    /// Allows stakes from `nft_contract` once it has a pool. Can only be called by the owner.
+    pub fn add_allowed_nft_contract(&mut self, nft_contract: AccountId) {
+        self.assert_owner();
+        self.allowed_nft_contracts.insert(&nft_contract);
+    }
+
+    /// Stops accepting new stakes from `nft_contract`. Existing stakes can still be unstaked and
+    /// keep earning rewards. Can only be called by the owner.
+    pub fn remove_allowed_nft_contract(&mut self, nft_contract: AccountId) {
+        self.assert_owner();
+        self.allowed_nft_contracts.remove(&nft_contract);
+    }
+
+    /// Returns the NFT contracts stakes are accepted from.
+    pub fn get_allowed_nft_contracts(&self) -> Vec<AccountId> {
+        self.allowed_nft_contracts.to_vec()
+    }
+
+    /// Restricts stakes from `nft_contract` to `token_ids`, replacing any previous restriction.
+    /// An empty list makes the whole collection eligible again. Existing stakes are kept. Can
+    /// only be called by the owner.
+    pub fn set_eligible_token_ids(&mut self, nft_contract: AccountId, token_ids: Vec<TokenId>) {
+        self.assert_owner();
+        if let Some(mut eligible) = self.eligible_token_ids.remove(&nft_contract) {
+            eligible.clear();
+        }
+        if token_ids.is_empty() {
+            return;
+        }
+        let mut prefix = b"e".to_vec();
+        prefix.extend(env::sha256(nft_contract.as_bytes()));
+        let mut eligible = UnorderedSet::new(prefix);
+        eligible.extend(token_ids);
+        self.eligible_token_ids.insert(&nft_contract, &eligible);
+    }
+
+    /// Returns the token ids of `nft_contract` that can be staked, or `None` if all of them can.
+    pub fn get_eligible_token_ids(&self, nft_contract: AccountId) -> Option<Vec<TokenId>> {
+        self.eligible_token_ids.get(&nft_contract).map(|eligible| eligible.to_vec())
+    }
+
+    /// Returns when the lock of `token_id` of `nft_contract` ends, in nanoseconds, if it is
+    /// staked with a lock.
+    pub fn view_unlock_time(&self, nft_contract: AccountId, token_id: TokenId) -> Option<u64> {
+        self.stakes.get(&(nft_contract, token_id)).and_then(|stake| stake.unlock_at)
+    }
+
+    /// Sets the share of rewards, in basis points, forfeited when unstaking before the lock ends.
+    /// Can only be called by the owner.
+    pub fn set_early_unstake_penalty_bps(&mut self, penalty_bps: u32) {
+        self.assert_owner();
+        assert!(penalty_bps <= BPS_DENOMINATOR, "Penalty can't exceed 10000 bps");
+        self.early_unstake_penalty_bps = penalty_bps;
+    }
+
+    /// Returns the share of rewards, in basis points, forfeited by an early unstake.
+    pub fn get_early_unstake_penalty_bps(&self) -> u32 {
+        self.early_unstake_penalty_bps
+    }
+
+    /// Sets the reward multiplier of `token_id` of `nft_contract` in basis points, e.g. 20000 for
+    /// 2x. If the token is staked, the rewards it accrued so far are settled at the old multiplier
+    /// first, and kept on the stake until it is claimed, compounded or unstaked. Can only be
+    /// called by the owner.
+    pub fn set_multiplier(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        multiplier_bps: u32,
+    ) {
+        self.assert_owner();
+        assert!(
+            (MIN_MULTIPLIER_BPS..=MAX_MULTIPLIER_BPS).contains(&multiplier_bps),
+            "Multiplier must be between {} and {} bps",
+            MIN_MULTIPLIER_BPS,
+            MAX_MULTIPLIER_BPS
+        );
+        let key = (nft_contract, token_id);
+        if let Some(mut stake) = self.stakes.get(&key) {
+            stake.accrued = self.internal_take_rewards(&key.1, &mut stake);
+            self.stakes.insert(&key, &stake);
+        }
+        self.multipliers.insert(&key, &multiplier_bps);
+    }
+
+    /// Returns the reward multiplier of `token_id` of `nft_contract` in basis points.
+    pub fn get_multiplier(&self, nft_contract: AccountId, token_id: TokenId) -> u32 {
+        self.internal_multiplier(&nft_contract, &token_id)
+    }
+
+    /// Returns the reward tokens available for claims.
+    pub fn get_reward_reserve(&self) -> U128 {
+        self.reward_reserve.into()
+    }
+
+    /// Sets the smallest unclaimed balance that can be claimed. Can only be called by the owner.
+    pub fn set_min_claim_amount(&mut self, min_claim_amount: U128) {
+        self.assert_owner();
+        self.min_claim_amount = min_claim_amount.into();
+    }
+
+    /// Returns the smallest unclaimed balance that can be claimed.
+    pub fn get_min_claim_amount(&self) -> U128 {
+        self.min_claim_amount.into()
+    }
+
+    /// Sets the most tokens an account can have staked at once. Existing stakes over the limit
+    /// are kept. Can only be called by the owner.
+    pub fn set_max_stakes_per_account(&mut self, max_stakes_per_account: u32) {
+        self.assert_owner();
+        self.max_stakes_per_account = max_stakes_per_account;
+    }
+
+    /// Returns the most tokens an account can have staked at once.
+    pub fn get_max_stakes_per_account(&self) -> u32 {
+        self.max_stakes_per_account
+    }
+
+    /// Returns the number of stakes, stakers and pools, and the reward reserve.
+    pub fn get_staking_stats(&self) -> StakingStats {
+        StakingStats {
+            total_stakes: self.total_stakes,
+            total_stakers: self.total_stakers,
+            total_pools: self.pools.len(),
+            reward_reserve: self.reward_reserve.into(),
+        }
+    }
+
+    /// Returns the settled rewards of `account_id` that haven't been paid out yet.
+    pub fn get_unclaimed_rewards(&self, account_id: AccountId) -> U128 {
+        self.unclaimed_rewards.get(&account_id).unwrap_or(0).into()
+    }
+
+    /// Returns the rewards paid out to `account_id` over all its claims, including those of
+    /// stakes that have been unstaked since.
+    pub fn get_account_lifetime_claimed(&self, account_id: AccountId) -> U128 {
+        self.account_lifetime_claimed.get(&account_id).unwrap_or(0).into()
+    }
+
+    /// Returns the rewards `token_id` of `nft_contract` has accrued since its last settlement.
+    pub fn view_pending_rewards(&self, nft_contract: AccountId, token_id: TokenId) -> U128 {
+        self.pending_rewards(&nft_contract, &token_id).into()
+    }
+
+    /// Returns the rewards `token_id` of `nft_contract` will have accrued since its last
+    /// settlement at `at_timestamp_sec`, in seconds, assuming nothing changes until then. Cliffs,
+    /// halvings and multipliers are applied, and if the stake would still be locked at that time,
+    /// the early unstake penalty is deducted as unstaking then would. Panics if the timestamp is
+    /// before the last settlement.
+    pub fn project_rewards(
+        &self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        at_timestamp_sec: u64,
+    ) -> U128 {
+        let stake = self.internal_get_stake(&nft_contract, &token_id);
+        assert!(
+            at_timestamp_sec >= stake.last_update / NANOS_PER_SECOND,
+            "Timestamp is before the last settlement of the stake"
+        );
+        let reward = self.internal_accrue(&token_id, &stake, at_timestamp_sec).reward;
+        let penalty = self.internal_early_unstake_penalty(
+            &stake,
+            reward,
+            at_timestamp_sec.saturating_mul(NANOS_PER_SECOND),
+        );
+        (reward - penalty).into()
+    }
+
+    /// Unstakes `token_id` of `nft_contract`, crediting its accrued rewards to the unclaimed
+    /// balance of its reward recipient, or of the caller, and transferring the NFT back to the
+    /// caller. If the stake is still locked, the credited rewards are reduced by
+    /// `early_unstake_penalty_bps`. If the NFT transfer fails, the stake is restored. A stake by
+    /// approval is removed once the caller is confirmed to still hold the token, see
+    /// `resolve_approval_unstake`. Can only be called by the staker.
+    pub fn unstake(&mut self, nft_contract: AccountId, token_id: TokenId) -> Promise {
+        let stake = self.internal_get_stake(&nft_contract, &token_id);
+        assert_eq!(stake.owner_id, env::predecessor_account_id(), "Only the staker can unstake");
+        if stake.approval_id.is_some() {
+            return ext_nft::nft_token(
+                token_id.clone(),
+                nft_contract.clone(),
+                0,
+                GAS_FOR_NFT_TOKEN,
+            )
+            .then(ext_self::resolve_approval_unstake(
+                nft_contract,
+                token_id,
+                env::current_account_id(),
+                0,
+                GAS_FOR_RESOLVE_APPROVAL,
+            ));
+        }
+        let (stake, reward) = self.internal_settle_unstake(&token_id, stake);
+        self.internal_return_nft(token_id, stake, reward, false)
+    }
+
+    /// Restakes `token_id` of `nft_contract` in place, locked for `new_lock_duration_sec` from now.
+    /// Its pending rewards are settled and credited to the unclaimed balance of its reward
+    /// recipient, or of the caller, and the stake restarts now, including the pool's cliff. While
+    /// the current lock is still active, the new lock has to end later, and only the lock is
+    /// extended: the stake isn't settled, so its rewards stay subject to the early unstake
+    /// penalty. Can only be called by the staker.
+    pub fn relock(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        new_lock_duration_sec: u64,
+    ) {
+        let mut stake = self.internal_get_stake(&nft_contract, &token_id);
+        assert_eq!(stake.owner_id, env::predecessor_account_id(), "Only the staker can relock");
+        assert!(stake.approval_id.is_none(), "Stakes by approval can't be locked");
+        let now = env::block_timestamp();
+        let unlock_at =
+            Self::internal_unlock_at(new_lock_duration_sec).expect("Lock duration is too long");
+        if let Some(current_unlock_at) = stake.unlock_at.filter(|&unlock_at| now < unlock_at) {
+            assert!(unlock_at > current_unlock_at, "Relocking can't shorten the current lock");
+        } else {
+            let reward = self.internal_take_rewards(&token_id, &mut stake);
+            self.internal_credit_stake(&mut stake, reward);
+            stake.staked_at = now;
+            stake.cliff_duration_sec =
+                self.internal_get_pool(&stake.nft_contract).cliff_duration_sec;
+            stake.paused_sec_at_stake = stake.paused_sec_at_update;
+        }
+        stake.unlock_at = Some(unlock_at);
+        let initial_storage_usage = env::storage_usage();
+        self.internal_insert_stake(&token_id, &stake);
+        self.internal_charge_storage(&stake.owner_id, initial_storage_usage);
+        log!("Token {} relocked until {}", token_id, unlock_at);
+    }
+
+    /// Unstakes `token_id` of `nft_contract` without crediting any rewards, and transfers the NFT
+    /// back to the caller. Its pending rewards and its compounded bonus weight are forfeited. Meant
+    /// for recovering NFTs when the reward token is broken. If the NFT transfer fails, the stake is
+    /// restored without the forfeited rewards. A stake by approval is removed right away. Can only
+    /// be called by the staker.
+    pub fn emergency_unstake(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+    ) -> PromiseOrValue<bool> {
+        let mut stake = self.internal_get_stake(&nft_contract, &token_id);
+        assert_eq!(stake.owner_id, env::predecessor_account_id(), "Only the staker can unstake");
+        // Marks the rewards as settled, so that a restored stake doesn't accrue them again.
+        let forfeited = self.internal_take_rewards(&token_id, &mut stake)
+            + Self::internal_take_bonus_weight(&mut stake);
+        if stake.approval_id.is_some() {
+            self.internal_remove_stake(&token_id, &stake);
+            Self::internal_emit_unstake(&token_id, &stake, forfeited, true);
+            PromiseOrValue::Value(true)
+        } else {
+            PromiseOrValue::Promise(self.internal_return_nft(token_id, stake, forfeited, true))
+        }
+    }
+
+    /// Callback after the NFT transfer of an unstake. Logs the unstake, or emergency unstake,
+    /// with `amount` once the NFT is returned, or restores the stake if the transfer failed, so
+    /// that the contract doesn't lose track of a token it still holds. Returns whether the NFT
+    /// was returned.
+    #[private]
+    pub fn resolve_unstake(
+        &mut self,
+        token_id: TokenId,
+        stake: Stake,
+        amount: U128,
+        emergency: bool,
+    ) -> bool {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                Self::internal_emit_unstake(&token_id, &stake, amount.0, emergency);
+                true
+            }
+            _ => {
+                log!("Failed to return token {}, restoring the stake", token_id);
+                let initial_storage_usage = env::storage_usage();
+                self.internal_insert_stake(&token_id, &stake);
+                self.internal_add_staker_token(&stake.owner_id, &token_id, &stake.nft_contract);
+                self.internal_update_total_staked(&stake.nft_contract, 1, 0);
+                self.internal_force_charge_storage(&stake.owner_id, initial_storage_usage);
+                false
+            }
+        }
+    }
+
+    /// Pays the rewards of `token_id` of `nft_contract` to `recipient` instead of the staker from
+    /// now on. Passing the staker removes the delegation. Unless the stake is locked, its pending
+    /// rewards are settled first and credited to the previous recipient, or the staker, which
+    /// keeps all rewards credited so far. The recipient pays for the storage of its unclaimed
+    /// balance, so it has to be registered. Can only be called by the staker.
+    pub fn set_reward_recipient(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        recipient: AccountId,
+    ) {
+        let mut stake = self.internal_get_stake(&nft_contract, &token_id);
+        assert_eq!(
+            stake.owner_id,
+            env::predecessor_account_id(),
+            "Only the staker can set the reward recipient"
+        );
+        let recipient = if recipient == stake.owner_id { None } else { Some(recipient) };
+        if let Some(recipient) = recipient.as_ref() {
+            assert!(
+                self.storage_accounts.contains_key(recipient),
+                "The reward recipient is not registered, it has to call storage_deposit first"
+            );
+        }
+        if recipient != stake.reward_recipient && !stake.is_locked(env::block_timestamp()) {
+            let reward = self.internal_take_rewards(&token_id, &mut stake);
+            self.internal_credit_stake(&mut stake, reward);
+        }
+        stake.reward_recipient = recipient;
+        let initial_storage_usage = env::storage_usage();
+        self.internal_insert_stake(&token_id, &stake);
+        self.internal_charge_storage(&stake.owner_id, initial_storage_usage);
+    }
+
+    /// Settles the rewards of `token_id` of `nft_contract` and pays the unclaimed balance of its
+    /// reward recipient, or of the caller if there is none, out in reward tokens. If the reserve
+    /// can't cover the whole balance, only the reserve is paid and the rest stays unclaimed. If the
+    /// transfer fails, the amount is credited back. A stake by approval is only settled once the
+    /// caller is confirmed to still hold the token, see `resolve_approval_claim`. Can only be
+    /// called by the staker, once the stake is unlocked.
+    pub fn claim_rewards(&mut self, nft_contract: AccountId, token_id: TokenId) -> Promise {
+        self.internal_claim_stake(nft_contract, token_id, None)
+    }
+
+    /// Like `claim_rewards`, but pays exactly `amount` out and leaves the rest of the unclaimed
+    /// balance for later claims. Panics if `amount` exceeds the unclaimed balance after settling
+    /// the stake, or the reserve. Can only be called by the staker.
+    pub fn claim_amount(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        amount: U128,
+    ) -> Promise {
+        assert!(amount.0 > 0, "The amount should be a positive number");
+        self.internal_claim_stake(nft_contract, token_id, Some(amount.0))
+    }
+
+    /// Settles up to 100 stakes of the caller, starting at `from_index` of their stakes, and pays
+    /// the caller's unclaimed balance out in a single reward transfer. Rewards of stakes with a
+    /// reward recipient are credited to the recipient instead. Stakes by approval are skipped, as
+    /// they have to be checked with `claim_rewards`, and so are locked stakes. An account without
+    /// stakes can use it to claim the rewards credited by its unstakes. Returns the number of
+    /// stakes handled, so that a client can continue from `from_index` plus that number.
+    pub fn claim_all_rewards(&mut self, from_index: Option<u64>) -> u64 {
+        let account_id = env::predecessor_account_id();
+        let keys: Vec<(AccountId, TokenId)> =
+            self.staker_tokens.get(&account_id).map_or_else(Vec::new, |tokens| {
+                tokens
+                    .as_vector()
+                    .iter()
+                    .skip(from_index.unwrap_or(0) as usize)
+                    .take(MAX_CLAIM_ALL_STAKES as usize)
+                    .collect()
+            });
+        let now = env::block_timestamp();
+        let mut claimed_stakes = Vec::new();
+        for (nft_contract, token_id) in keys.iter() {
+            let mut stake = self.internal_get_stake(nft_contract, token_id);
+            if stake.approval_id.is_some() || stake.is_locked(now) {
+                continue;
+            }
+            let reward = self.internal_take_rewards(token_id, &mut stake);
+            self.internal_credit_stake(&mut stake, reward);
+            if stake.reward_recipient.is_none() {
+                claimed_stakes.push((token_id.clone(), stake));
+            } else {
+                self.internal_insert_stake(token_id, &stake);
+            }
+        }
+        self.internal_pay_out(account_id, None, None, claimed_stakes);
+        keys.len() as u64
+    }
+
+    /// Settles the pending rewards of `token_id` of `nft_contract` into its bonus weight instead of
+    /// crediting them, raising the rewards it earns from now on. The bonus weight is credited back
+    /// on unstake, subject to the early unstake penalty, but forfeited by an emergency unstake or
+    /// if a stake by approval is cancelled. Does nothing if no rewards are pending. Returns the
+    /// compounded amount. A stake by approval is only compounded once the caller is confirmed to
+    /// still hold the token, see `resolve_approval_compound`. Can only be called by the staker,
+    /// once the stake is unlocked.
+    pub fn compound(&mut self, nft_contract: AccountId, token_id: TokenId) -> PromiseOrValue<U128> {
+        let stake = self.internal_get_stake(&nft_contract, &token_id);
+        assert_eq!(
+            stake.owner_id,
+            env::predecessor_account_id(),
+            "Only the staker can compound rewards"
+        );
+        assert!(
+            !stake.is_locked(env::block_timestamp()),
+            "Rewards of a locked stake can't be compounded"
+        );
+        if stake.approval_id.is_some() {
+            return PromiseOrValue::Promise(
+                ext_nft::nft_token(token_id.clone(), nft_contract.clone(), 0, GAS_FOR_NFT_TOKEN)
+                    .then(ext_self::resolve_approval_compound(
+                        nft_contract,
+                        token_id,
+                        env::current_account_id(),
+                        0,
+                        GAS_FOR_RESOLVE_APPROVAL,
+                    )),
+            );
+        }
+        PromiseOrValue::Value(self.internal_compound(&token_id, stake))
+    }
+
+    /// Callback after the reward transfer of a claim. If the transfer succeeded, logs the claim
+    /// event, with `nft_contract` and `token_id` of the claimed stake if the claim was for a single
+    /// one, and adds `amount` to the lifetime claims of `account_id` and the `claimed` share of
+    /// each stake to its own. Credits `amount` back to the unclaimed balance of `account_id`, the
+    /// reserve and the emission budget if it failed, returning the shares to the unclaimed
+    /// rewards of their stakes. Returns the amount that was paid out.
+    #[private]
+    pub fn on_reward_claimed(
+        &mut self,
+        account_id: AccountId,
+        nft_contract: Option<AccountId>,
+        token_id: Option<TokenId>,
+        amount: U128,
+        claimed: Vec<(AccountId, TokenId, U128)>,
+    ) -> U128 {
+        match env::promise_result(0) {
+            PromiseResult::Successful(_) => {
+                StakingEvent::Claim([ClaimData {
+                    account_id: &account_id,
+                    nft_contract: nft_contract.as_ref(),
+                    token_id: token_id.as_ref(),
+                    amount,
+                }])
+                .emit();
+                let initial_storage_usage = env::storage_usage();
+                let lifetime_claimed = self.account_lifetime_claimed.get(&account_id).unwrap_or(0);
+                self.account_lifetime_claimed.insert(&account_id, &(lifetime_claimed + amount.0));
+                self.internal_force_charge_storage(&account_id, initial_storage_usage);
+                for (nft_contract, token_id, share) in claimed {
+                    // The token may have been unstaked while the transfer was in flight.
+                    let key = (nft_contract, token_id);
+                    if let Some(mut stake) = self.stakes.get(&key) {
+                        stake.lifetime_claimed += share.0;
+                        self.stakes.insert(&key, &stake);
+                    }
+                }
+                amount
+            }
+            _ => {
+                log!("Failed to pay {} rewards to @{}, crediting them back", amount.0, account_id);
+                for (nft_contract, token_id, share) in claimed {
+                    let key = (nft_contract, token_id);
+                    if let Some(mut stake) = self.stakes.get(&key) {
+                        stake.unclaimed += share.0;
+                        self.stakes.insert(&key, &stake);
+                    }
+                }
+                self.internal_credit(&account_id, amount.into());
+                self.reward_reserve += amount.0;
+                self.internal_revert_emission(amount.0);
+                U128(0)
+            }
+        }
+    }
+
+    /// Stakes `token_id` of `nft_contract` for the caller while the NFT stays in their wallet.
+    /// The caller must have approved this contract for the token with `approval_id`, which is
+    /// checked with an `nft_token` call before the stake is recorded in `resolve_approval_stake`.
+    /// The same rules as for staking by transfer apply, except that the stake can't be locked.
+    pub fn stake_with_approval(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        approval_id: u64,
+    ) -> Promise {
+        let account_id = env::predecessor_account_id();
+        if let Some(refusal) = self.internal_stake_refusal(&account_id, &nft_contract, &token_id) {
+            panic!("{}", refusal);
+        }
+        ext_nft::nft_token(token_id.clone(), nft_contract.clone(), 0, GAS_FOR_NFT_TOKEN).then(
+            ext_self::resolve_approval_stake(
+                account_id,
+                nft_contract,
+                token_id,
+                approval_id,
+                env::current_account_id(),
+                0,
+                GAS_FOR_RESOLVE_APPROVAL,
+            ),
+        )
+    }
+
+    /// Callback of `stake_with_approval`. Records the stake if `account_id` owns the token and
+    /// this contract holds `approval_id` for it, and panics otherwise.
+    #[private]
+    pub fn resolve_approval_stake(
+        &mut self,
+        account_id: AccountId,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        approval_id: u64,
+    ) {
+        let token = Self::internal_token_from_promise().expect("Token doesn't exist");
+        assert_eq!(token.owner_id, account_id, "Token is not owned by the staker");
+        assert_eq!(
+            token.approved_account_ids.and_then(|ids| ids.get(&env::current_account_id()).copied()),
+            Some(approval_id),
+            "Staking contract is not approved for the token"
+        );
+        // Checked again, as the state may have changed while waiting for the NFT contract.
+        if let Some(refusal) = self.internal_stake_refusal(&account_id, &nft_contract, &token_id) {
+            panic!("{}", refusal);
+        }
+        self.internal_stake(account_id, nft_contract, token_id, None, Some(approval_id));
+    }
+
+    /// Callback of `claim_rewards` and `claim_amount` for a stake by approval. Claims the rewards
+    /// if the staker still holds the token, or cancels the stake otherwise. Returns the amount
+    /// paid out.
+    #[private]
+    pub fn resolve_approval_claim(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        amount: Option<U128>,
+    ) -> PromiseOrValue<U128> {
+        let stake = self.internal_get_stake(&nft_contract, &token_id);
+        if Self::internal_staker_holds_token(&stake) {
+            let amount = amount.map(|amount| amount.0);
+            PromiseOrValue::Promise(self.internal_claim(token_id, stake, amount))
+        } else {
+            self.internal_cancel_stake(token_id, stake);
+            PromiseOrValue::Value(U128(0))
+        }
+    }
+
+    /// Callback of `unstake` for a stake by approval. Unstakes the token like `unstake` if the
+    /// staker still holds it, or cancels the stake otherwise. Returns whether rewards were
+    /// credited.
+    #[private]
+    pub fn resolve_approval_unstake(&mut self, nft_contract: AccountId, token_id: TokenId) -> bool {
+        let stake = self.internal_get_stake(&nft_contract, &token_id);
+        if Self::internal_staker_holds_token(&stake) {
+            let (stake, reward) = self.internal_settle_unstake(&token_id, stake);
+            self.internal_remove_stake(&token_id, &stake);
+            Self::internal_emit_unstake(&token_id, &stake, reward, false);
+            true
+        } else {
+            self.internal_cancel_stake(token_id, stake);
+            false
+        }
+    }
+
+    /// Callback of `compound` for a stake by approval. Compounds the rewards like `compound` if
+    /// the staker still holds the token, or cancels the stake otherwise. Returns the compounded
+    /// amount.
+    #[private]
+    pub fn resolve_approval_compound(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+    ) -> U128 {
+        let stake = self.internal_get_stake(&nft_contract, &token_id);
+        if Self::internal_staker_holds_token(&stake) {
+            self.internal_compound(&token_id, stake)
+        } else {
+            self.internal_cancel_stake(token_id, stake);
+            U128(0)
+        }
+    }
+
+    fn pending_rewards(&self, nft_contract: &AccountId, token_id: &TokenId) -> Balance {
+        let stake = self.internal_get_stake(nft_contract, token_id);
+        self.internal_pending_rewards(token_id, &stake)
+    }
+
+    /// Stake of `token_id` of `nft_contract`. Panics if the token isn't staked.
+    fn internal_get_stake(&self, nft_contract: &AccountId, token_id: &TokenId) -> Stake {
+        self.stakes.get(&(nft_contract.clone(), token_id.clone())).expect("Token is not staked")
+    }
+
+    /// Saves `stake` of `token_id` under the NFT contract it was staked from.
+    fn internal_insert_stake(&mut self, token_id: &TokenId, stake: &Stake) {
+        self.stakes.insert(&(stake.nft_contract.clone(), token_id.clone()), stake);
+    }
+
+    fn internal_fetch_reward_token_decimals(&self) -> Promise {
+        ext_ft::ft_metadata(self.reward_token_id.clone(), 0, GAS_FOR_FT_METADATA).then(
+            ext_self::on_reward_token_metadata(
+                env::current_account_id(),
+                0,
+                GAS_FOR_RESOLVE_METADATA,
+            ),
+        )
+    }
+
+    /// Rewards accrued by `stake` of `token_id` since its last settlement.
+    fn internal_pending_rewards(&self, token_id: &TokenId, stake: &Stake) -> Balance {
+        self.internal_accrue(token_id, stake, env::block_timestamp() / NANOS_PER_SECOND).reward
+    }
+
+    /// Rewards accrued by `stake` of `token_id` from its last settlement up to `to_sec`, in
+    /// seconds. Only whole seconds are counted, but because both timestamps are truncated the same
+    /// way, no time is lost across settlements.
+    ///
+    /// Time is measured on the pool's clock, which stops while the pool is paused, so paused
+    /// seconds don't count, nor towards the cliff and halvings. Each second earns the pool rate in
+    /// effect at that second scaled by the token's multiplier and by the pool's set bonus if the
+    /// staker currently qualifies for it, plus the share of `COMPOUND_APR_BPS` of the stake's
+    /// `bonus_weight` that falls on that second. The remainders carried by the stake are added
+    /// before dividing. Seconds within the stake's cliff after it started don't count. The
+    /// rewards the stake accrued before its last settlement, but kept, are included.
+    fn internal_accrue(&self, token_id: &TokenId, stake: &Stake, to_sec: u64) -> rewards::Accrual {
+        let pool = self.internal_get_pool(&stake.nft_contract);
+        let paused_sec = pool.paused_sec_at(to_sec);
+        let staked_sec = stake.staked_at / NANOS_PER_SECOND - stake.paused_sec_at_stake;
+        // Nothing accrues during the cliff, and the cliff seconds aren't paid afterwards.
+        let from_sec = (stake.last_update / NANOS_PER_SECOND - stake.paused_sec_at_update)
+            .max(staked_sec + stake.cliff_duration_sec);
+        let to_sec = to_sec.saturating_sub(paused_sec);
+        let elapsed_seconds = Balance::from(to_sec.saturating_sub(from_sec));
+        let multiplier_bps = Balance::from(self.internal_multiplier(&stake.nft_contract, token_id));
+        let set_bonus_bps = Balance::from(self.internal_set_bonus_bps(stake, &pool));
+        // Computed in u128 so that long staking durations can't overflow. No time passed on the
+        // pool's clock before its genesis, so the halving schedule starts there too.
+        let (base_reward, base_remainder) = rewards::halving_rewards(
+            pool.reward_rate_per_second,
+            pool.genesis_timestamp / NANOS_PER_SECOND,
+            pool.halving_interval_sec,
+            from_sec,
+            to_sec,
+        )
+        .and_then(|reward| reward.checked_mul(multiplier_bps))
+        .and_then(|reward| reward.checked_mul(Balance::from(BPS_DENOMINATOR) + set_bonus_bps))
+        .and_then(|reward| {
+            rewards::divide_with_carry(
+                reward,
+                stake.base_remainder,
+                Balance::from(BPS_DENOMINATOR) * Balance::from(BPS_DENOMINATOR),
+            )
+        })
+        .expect("Reward overflow");
+        let (bonus_reward, bonus_remainder) = elapsed_seconds
+            .checked_mul(stake.bonus_weight)
+            .and_then(|reward| reward.checked_mul(Balance::from(COMPOUND_APR_BPS)))
+            .and_then(|reward| {
+                rewards::divide_with_carry(
+                    reward,
+                    stake.bonus_remainder,
+                    Balance::from(BPS_DENOMINATOR) * Balance::from(SECONDS_PER_YEAR),
+                )
+            })
+            .expect("Reward overflow");
+        rewards::Accrual {
+            reward: stake.accrued + base_reward + bonus_reward,
+            base_remainder,
+            bonus_remainder,
+            paused_sec,
+        }
+    }
+
+    /// Returns the pending rewards of `stake` of `token_id` and marks them as settled, keeping
+    /// the remainders. The caller is responsible for crediting them.
+    fn internal_take_rewards(&self, token_id: &TokenId, stake: &mut Stake) -> Balance {
+        let accrual =
+            self.internal_accrue(token_id, stake, env::block_timestamp() / NANOS_PER_SECOND);
+        stake.last_update = env::block_timestamp();
+        stake.paused_sec_at_update = accrual.paused_sec;
+        stake.accrued = 0;
+        stake.base_remainder = accrual.base_remainder;
+        stake.bonus_remainder = accrual.bonus_remainder;
+        accrual.reward
+    }
+
+    /// Pays the unclaimed balance of `account_id` out in reward tokens, up to the reserve, or
+    /// exactly `amount` of it if given. `token` is the NFT contract and id of the claimed stake,
+    /// if the claim is for a single one. The payout is attributed to `stakes` of `account_id` in
+    /// order, up to the unclaimed rewards of each, for their lifetime claims. The stakes are saved
+    /// with the attributed shares taken out of their unclaimed rewards.
+    fn internal_pay_out(
+        &mut self,
+        account_id: AccountId,
+        token: Option<(&AccountId, &TokenId)>,
+        amount: Option<Balance>,
+        stakes: Vec<(TokenId, Stake)>,
+    ) -> Promise {
+        let initial_storage_usage = env::storage_usage();
+        let unclaimed = self.unclaimed_rewards.remove(&account_id).unwrap_or(0);
+        assert!(unclaimed > 0, "No rewards to claim");
+        self.internal_force_charge_storage(&account_id, initial_storage_usage);
+        assert!(
+            unclaimed >= self.min_claim_amount,
+            "Pending rewards below minimum claim threshold"
+        );
+        assert!(self.reward_reserve > 0, "Reward reserve is empty");
+        let budget = self.internal_emission_budget().unwrap_or(Balance::MAX);
+        let amount = match amount {
+            Some(amount) => {
+                assert!(amount <= unclaimed, "The amount exceeds the claimable balance");
+                assert!(amount <= self.reward_reserve, "The amount exceeds the reward reserve");
+                assert!(amount <= budget, "The amount exceeds the emission budget of this epoch");
+                amount
+            }
+            None => {
+                assert!(budget > 0, "The emission budget of this epoch is exhausted");
+                let amount = unclaimed.min(self.reward_reserve);
+                if amount < unclaimed {
+                    log!(
+                        "Reward reserve only covers {} of {} unclaimed rewards",
+                        amount,
+                        unclaimed
+                    );
+                }
+                if budget < amount {
+                    log!("Emission budget only covers {}, deferring the rest", budget);
+                }
+                amount.min(budget)
+            }
+        };
+        self.internal_record_emission(amount);
+        self.internal_credit(&account_id, unclaimed - amount);
+        self.reward_reserve -= amount;
+        let mut unattributed = amount;
+        let mut claimed = Vec::new();
+        for (token_id, mut stake) in stakes {
+            let share = stake.unclaimed.min(unattributed);
+            if share > 0 {
+                unattributed -= share;
+                stake.unclaimed -= share;
+                claimed.push((stake.nft_contract.clone(), token_id.clone(), U128(share)));
+            }
+            self.internal_insert_stake(&token_id, &stake);
+        }
+        ext_ft::ft_transfer(
+            account_id.clone(),
+            amount.into(),
+            Some("Staking rewards".to_string()),
+            self.reward_token_id.clone(),
+            ONE_YOCTO,
+            GAS_FOR_FT_TRANSFER,
+        )
+        .then(ext_self::on_reward_claimed(
+            account_id,
+            token.map(|(nft_contract, _)| nft_contract.clone()),
+            token.map(|(_, token_id)| token_id.clone()),
+            amount.into(),
+            claimed,
+            env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_CLAIM,
+        ))
+    }
+
+    /// Removes the stake of `token_id`, releasing its storage, and transfers the NFT back to the
+    /// staker. `resolve_unstake` then logs the unstake with `amount`, or restores the stake if the
+    /// transfer failed.
+    fn internal_return_nft(
+        &mut self,
+        token_id: TokenId,
+        stake: Stake,
+        amount: Balance,
+        emergency: bool,
+    ) -> Promise {
+        self.internal_remove_stake(&token_id, &stake);
+        ext_nft::nft_transfer(
+            stake.owner_id.clone(),
+            token_id.clone(),
+            None,
+            Some("Unstaked".to_string()),
+            stake.nft_contract.clone(),
+            ONE_YOCTO,
+            GAS_FOR_NFT_TRANSFER,
+        )
+        .then(ext_self::resolve_unstake(
+            token_id,
+            stake,
+            amount.into(),
+            emergency,
+            env::current_account_id(),
+            0,
+            GAS_FOR_RESOLVE_UNSTAKE,
+        ))
+    }
+
+    /// Removes the stake of `token_id`, releasing its storage.
+    fn internal_remove_stake(&mut self, token_id: &TokenId, stake: &Stake) {
+        let initial_storage_usage = env::storage_usage();
+        self.stakes.remove(&(stake.nft_contract.clone(), token_id.clone()));
+        self.internal_remove_staker_token(&stake.owner_id, token_id, &stake.nft_contract);
+        self.internal_update_total_staked(&stake.nft_contract, 0, 1);
+        self.internal_charge_storage(&stake.owner_id, initial_storage_usage);
+    }
+
+    /// Removes a stake by approval whose staker no longer holds the token. Its rewards, including
+    /// its compounded bonus weight, are forfeited.
+    fn internal_cancel_stake(&mut self, token_id: TokenId, stake: Stake) {
+        log!("@{} no longer holds token {}, cancelling its stake", stake.owner_id, token_id);
+        self.internal_remove_stake(&token_id, &stake);
+        Self::internal_emit_unstake(&token_id, &stake, 0, false);
+    }
+
+    /// Logs the unstake of `stake` of `token_id` with the credited `amount`, or its emergency
+    /// unstake with the forfeited `amount`.
+    fn internal_emit_unstake(token_id: &TokenId, stake: &Stake, amount: Balance, emergency: bool) {
+        if emergency {
+            StakingEvent::EmergencyUnstake([EmergencyUnstakeData {
+                account_id: &stake.owner_id,
+                token_id,
+                nft_contract: &stake.nft_contract,
+                amount: amount.into(),
+            }])
+            .emit();
+        } else {
+            StakingEvent::Unstake([UnstakeData {
+                account_id: &stake.owner_id,
+                token_id,
+                nft_contract: &stake.nft_contract,
+                amount: amount.into(),
+            }])
+            .emit();
+        }
+    }
+
+    /// Settles the rewards of `stake` of `token_id` for unstaking, together with its bonus weight,
+    /// applying the early unstake penalty, and credits them to its reward recipient, or the
+    /// staker. Returns the stake and the credited amount.
+    fn internal_settle_unstake(
+        &mut self,
+        token_id: &TokenId,
+        mut stake: Stake,
+    ) -> (Stake, Balance) {
+        let mut reward = self.internal_take_rewards(token_id, &mut stake)
+            + Self::internal_take_bonus_weight(&mut stake);
+        let penalty = self.internal_early_unstake_penalty(&stake, reward, env::block_timestamp());
+        if penalty > 0 {
+            log!("Token {} unstaked before its lock ended, forfeiting {}", token_id, penalty);
+            reward -= penalty;
+        }
+        self.internal_credit(stake.claimant(), reward);
+        (stake, reward)
+    }
+
+    /// Clears the compounded bonus weight of `stake` as the stake ends, so that a stake restored
+    /// after a failed NFT transfer doesn't settle it again. Returns the weight.
+    fn internal_take_bonus_weight(stake: &mut Stake) -> Balance {
+        let bonus_weight = stake.bonus_weight;
+        stake.bonus_weight = 0;
+        stake.bonus_remainder = 0;
+        bonus_weight
+    }
+
+    /// Settles the pending rewards of `stake` of `token_id` into its bonus weight. Returns the
+    /// compounded amount.
+    fn internal_compound(&mut self, token_id: &TokenId, mut stake: Stake) -> U128 {
+        let reward = self.internal_take_rewards(token_id, &mut stake);
+        if reward == 0 {
+            return U128(0);
+        }
+        stake.bonus_weight += reward;
+        self.internal_insert_stake(token_id, &stake);
+        log!("Compounded {} rewards of token {}", reward, token_id);
+        reward.into()
+    }
+
+    /// Claims `amount`, or all, of the rewards of `token_id` for the caller, checking first that
+    /// a stake by approval is still held.
+    fn internal_claim_stake(
+        &mut self,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        amount: Option<Balance>,
+    ) -> Promise {
+        let stake = self.internal_get_stake(&nft_contract, &token_id);
+        assert_eq!(
+            stake.owner_id,
+            env::predecessor_account_id(),
+            "Only the staker can claim rewards"
+        );
+        assert!(
+            !stake.is_locked(env::block_timestamp()),
+            "Rewards of a locked stake can't be claimed"
+        );
+        if stake.approval_id.is_some() {
+            return ext_nft::nft_token(
+                token_id.clone(),
+                nft_contract.clone(),
+                0,
+                GAS_FOR_NFT_TOKEN,
+            )
+            .then(ext_self::resolve_approval_claim(
+                nft_contract,
+                token_id,
+                amount.map(U128),
+                env::current_account_id(),
+                0,
+                GAS_FOR_RESOLVE_APPROVAL,
+            ));
+        }
+        self.internal_claim(token_id, stake, amount)
+    }
+
+    /// Settles the rewards of `stake` of `token_id` and pays the unclaimed balance of its reward
+    /// recipient, or of the staker, out. Only `amount` is paid if given.
+    fn internal_claim(
+        &mut self,
+        token_id: TokenId,
+        mut stake: Stake,
+        amount: Option<Balance>,
+    ) -> Promise {
+        let account_id = stake.claimant().clone();
+        let nft_contract = stake.nft_contract.clone();
+        let reward = self.internal_take_rewards(&token_id, &mut stake);
+        self.internal_credit_stake(&mut stake, reward);
+        let stakes = vec![(token_id.clone(), stake)];
+        self.internal_pay_out(account_id, Some((&nft_contract, &token_id)), amount, stakes)
+    }
+
+    /// Records a stake of `token_id` of `nft_contract` for `account_id`, charging its storage to
+    /// the account's deposit. A stake by approval of the token by another account, which the
+    /// refusal check lets through, is cancelled first.
+    fn internal_stake(
+        &mut self,
+        account_id: AccountId,
+        nft_contract: AccountId,
+        token_id: TokenId,
+        unlock_at: Option<u64>,
+        approval_id: Option<u64>,
+    ) {
+        if let Some(stake) = self.stakes.get(&(nft_contract.clone(), token_id.clone())) {
+            self.internal_cancel_stake(token_id.clone(), stake);
+        }
+        let now = env::block_timestamp();
+        let pool = self.internal_get_pool(&nft_contract);
+        let paused_sec = pool.paused_sec_at(now / NANOS_PER_SECOND);
+        let initial_storage_usage = env::storage_usage();
+        self.internal_add_staker_token(&account_id, &token_id, &nft_contract);
+        StakingEvent::Stake([StakeData {
+            account_id: &account_id,
+            token_id: &token_id,
+            nft_contract: &nft_contract,
+        }])
+        .emit();
+        self.stakes.insert(
+            &(nft_contract.clone(), token_id.clone()),
+            &Stake {
+                owner_id: account_id.clone(),
+                nft_contract: nft_contract.clone(),
+                staked_at: now,
+                last_update: now,
+                cliff_duration_sec: pool.cliff_duration_sec,
+                paused_sec_at_stake: paused_sec,
+                paused_sec_at_update: paused_sec,
+                unlock_at,
+                bonus_weight: 0,
+                accrued: 0,
+                base_remainder: 0,
+                bonus_remainder: 0,
+                reward_recipient: None,
+                approval_id,
+                lifetime_claimed: 0,
+                unclaimed: 0,
+            },
+        );
+        self.internal_update_total_staked(&nft_contract, 1, 0);
+        self.internal_charge_storage(&account_id, initial_storage_usage);
+    }
+
+    /// Reason `account_id` can't stake `token_id` of `nft_contract` now, if any. A stake by
+    /// approval of the token by another account doesn't count, as only one account can hold the
+    /// token and the caller has seen that `account_id` does.
+    fn internal_stake_refusal(
+        &self,
+        account_id: &AccountId,
+        nft_contract: &AccountId,
+        token_id: &TokenId,
+    ) -> Option<String> {
+        if !self.allowed_nft_contracts.contains(nft_contract) {
+            return Some(format!("NFT contract @{} is not allowed", nft_contract));
+        }
+        if !self.pools.get(nft_contract).map_or(false, |pool| pool.enabled) {
+            return Some(format!("NFT contract @{} has no enabled pool", nft_contract));
+        }
+        if !self
+            .eligible_token_ids
+            .get(nft_contract)
+            .map_or(true, |eligible| eligible.contains(token_id))
+        {
+            return Some(format!(
+                "Token {} of @{} is not eligible for staking",
+                token_id, nft_contract
+            ));
+        }
+        if self
+            .stakes
+            .get(&(nft_contract.clone(), token_id.clone()))
+            .map_or(false, |stake| stake.approval_id.is_none() || &stake.owner_id == account_id)
+        {
+            return Some(format!("Token {} is already staked", token_id));
+        }
+        let stake_count = self.staker_tokens.get(account_id).map_or(0, |tokens| tokens.len());
+        if stake_count >= u64::from(self.max_stakes_per_account) {
+            return Some(format!("@{} already has {} stakes", account_id, stake_count));
+        }
+        None
+    }
+
+    /// Token returned by the `nft_token` call this is a callback of. Panics if the call failed,
+    /// so that a broken NFT contract can't cancel stakes.
+    fn internal_token_from_promise() -> Option<Token> {
+        match env::promise_result(0) {
+            PromiseResult::Successful(value) => {
+                serde_json::from_slice(&value).expect("Invalid nft_token result")
+            }
+            _ => panic!("Failed to fetch the token"),
+        }
+    }
+
+    /// Whether the staker of `stake` still holds the token, with the approval of the stake still
+    /// in place, according to the `nft_token` call this is a callback of.
+    fn internal_staker_holds_token(stake: &Stake) -> bool {
+        Self::internal_token_from_promise().map_or(false, |token| {
+            token.owner_id == stake.owner_id
+                && token
+                    .approved_account_ids
+                    .and_then(|ids| ids.get(&env::current_account_id()).copied())
+                    == stake.approval_id
+        })
+    }
+
+    fn stake_view(&self, token_id: TokenId, stake: Stake) -> StakeView {
+        let pending_rewards = self.internal_pending_rewards(&token_id, &stake).into();
+        StakeView {
+            token_id,
+            owner_id: stake.owner_id,
+            nft_contract: stake.nft_contract,
+            staked_at: stake.staked_at,
+            unlock_at: stake.unlock_at,
+            bonus_weight: stake.bonus_weight.into(),
+            pending_rewards,
+            reward_recipient: stake.reward_recipient,
+            approval_id: stake.approval_id,
+            lifetime_claimed: stake.lifetime_claimed.into(),
+        }
+    }
+
+    fn internal_add_staker_token(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &TokenId,
+        nft_contract: &AccountId,
+    ) {
+        let mut tokens = self.staker_tokens.get(account_id).unwrap_or_else(|| {
+            self.total_stakers += 1;
+            let mut prefix = b"o".to_vec();
+            prefix.extend(env::sha256(account_id.as_bytes()));
+            UnorderedSet::new(prefix)
+        });
+        tokens.insert(&(nft_contract.clone(), token_id.clone()));
+        self.staker_tokens.insert(account_id, &tokens);
+        self.total_stakes += 1;
+        let key = (account_id.clone(), nft_contract.clone());
+        let count = self.collection_stake_counts.get(&key).unwrap_or(0);
+        self.collection_stake_counts.insert(&key, &(count + 1));
+    }
+
+    fn internal_remove_staker_token(
+        &mut self,
+        account_id: &AccountId,
+        token_id: &TokenId,
+        nft_contract: &AccountId,
+    ) {
+        let mut tokens = self.staker_tokens.get(account_id).expect("Staker has no tokens");
+        tokens.remove(&(nft_contract.clone(), token_id.clone()));
+        self.total_stakes -= 1;
+        if tokens.is_empty() {
+            self.staker_tokens.remove(account_id);
+            self.total_stakers -= 1;
+        } else {
+            self.staker_tokens.insert(account_id, &tokens);
+        }
+        let key = (account_id.clone(), nft_contract.clone());
+        match self.collection_stake_counts.get(&key).unwrap_or(0) {
+            0 | 1 => self.collection_stake_counts.remove(&key),
+            count => self.collection_stake_counts.insert(&key, &(count - 1)),
+        };
+    }
+
+    /// Extra rate of `stake` from the set bonus of its pool, in basis points.
+    fn internal_set_bonus_bps(&self, stake: &Stake, pool: &Pool) -> u32 {
+        if pool.set_bonus_bps == 0 {
+            return 0;
+        }
+        let count = self
+            .collection_stake_counts
+            .get(&(stake.owner_id.clone(), stake.nft_contract.clone()))
+            .unwrap_or(0);
+        if count >= pool.set_bonus_threshold {
+            pool.set_bonus_bps
+        } else {
+            0
+        }
+    }
+
+    /// Part of `reward` forfeited if `stake` is unstaked at `timestamp`, in nanoseconds.
+    fn internal_early_unstake_penalty(
+        &self,
+        stake: &Stake,
+        reward: Balance,
+        timestamp: u64,
+    ) -> Balance {
+        if stake.is_locked(timestamp) {
+            reward * Balance::from(self.early_unstake_penalty_bps) / Balance::from(BPS_DENOMINATOR)
+        } else {
+            0
+        }
+    }
+
+    /// Block timestamp a lock of `lock_duration_sec` from now ends at, in nanoseconds, or `None`
+    /// if it is too far away to be represented.
+    fn internal_unlock_at(lock_duration_sec: u64) -> Option<u64> {
+        lock_duration_sec
+            .checked_mul(NANOS_PER_SECOND)
+            .and_then(|duration| env::block_timestamp().checked_add(duration))
+    }
+
+    fn internal_multiplier(&self, nft_contract: &AccountId, token_id: &TokenId) -> u32 {
+        self.multipliers.get(&(nft_contract.clone(), token_id.clone())).unwrap_or(BPS_DENOMINATOR)
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can call this method"
+        );
+    }
+
+    /// Adds `amount` to the unclaimed balance of `account_id`, charging a new balance to its
+    /// storage deposit. As crediting can't fail, the deposit may end up not covering it.
+    fn internal_credit(&mut self, account_id: &AccountId, amount: Balance) {
+        if amount > 0 {
+            let initial_storage_usage = env::storage_usage();
+            let unclaimed = self.unclaimed_rewards.get(account_id).unwrap_or(0);
+            self.unclaimed_rewards.insert(account_id, &(unclaimed + amount));
+            self.internal_force_charge_storage(account_id, initial_storage_usage);
+        }
+    }
+
+    /// Credits `reward` settled from `stake` to the unclaimed balance of its claimant, and counts
+    /// it as unclaimed rewards of the stake. The caller is responsible for saving the stake.
+    fn internal_credit_stake(&mut self, stake: &mut Stake, reward: Balance) {
+        self.internal_credit(stake.claimant(), reward);
+        stake.unclaimed += reward;
+    }
+}
+
+#[near_bindgen]
+impl NonFungibleTokenReceiver for Contract {
+    /// Stakes `token_id` for `previous_owner_id`, locked for `lock_duration_sec` if `msg` asks for
+    /// it. Returns `false` to keep custody of the NFT, or `true` to have the NFT contract return it
+    /// if the collection isn't allowed or has no enabled pool, the token isn't eligible or is
+    /// already staked, the staker is at `max_stakes_per_account` or `msg` is invalid or asks for a
+    /// lock too long to represent. A stake by
+    /// approval of the token by another account is cancelled. Panics, which also returns the NFT,
+    /// if the staker's storage deposit doesn't cover the stake.
+    #[allow(unused_variables)]
+    fn nft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        previous_owner_id: AccountId,
+        token_id: TokenId,
+        msg: String,
+    ) -> PromiseOrValue<bool> {
+        let nft_contract = env::predecessor_account_id();
+        if let Some(refusal) =
+            self.internal_stake_refusal(&previous_owner_id, &nft_contract, &token_id)
+        {
+            log!("{}", refusal);
+            return PromiseOrValue::Value(true);
+        }
+        let lock_duration_sec = if msg.is_empty() {
+            None
+        } else {
+            match serde_json::from_str::<StakeArgs>(&msg) {
+                Ok(args) => args.lock_duration_sec,
+                Err(_) => {
+                    log!("Invalid msg: {}", msg);
+                    return PromiseOrValue::Value(true);
+                }
+            }
+        };
+        let unlock_at = match lock_duration_sec {
+            Some(duration) => match Self::internal_unlock_at(duration) {
+                Some(unlock_at) => Some(unlock_at),
+                None => {
+                    log!("Lock duration is too long: {}", duration);
+                    return PromiseOrValue::Value(true);
+                }
+            },
+            None => None,
+        };
+        self.internal_stake(previous_owner_id, nft_contract, token_id, unlock_at, None);
+        PromiseOrValue::Value(false)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenReceiver for Contract {
+    /// Adds reward tokens sent with `ft_transfer_call` to the reward reserve. Tokens of any other
+    /// contract are refunded.
+    #[allow(unused_variables)]
+    fn ft_on_transfer(
+        &mut self,
+        sender_id: AccountId,
+        amount: U128,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        if env::predecessor_account_id() != self.reward_token_id {
+            log!("Only @{} can fund the reward reserve", self.reward_token_id);
+            return PromiseOrValue::Value(amount);
+        }
+        self.reward_reserve += amount.0;
+        log!("@{} added {} to the reward reserve", sender_id, amount.0);
+        PromiseOrValue::Value(U128(0))
+    }
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod tests {
+    use std::collections::HashMap;
+
+    use near_contract_standards::storage_management::StorageManagement;
+    use near_sdk::test_utils::{self, accounts, VMContextBuilder};
+    use near_sdk::testing_env;
+    use near_sdk::MockedBlockchain;
+
+    use super::*;
+
+    const REWARD_RATE: Balance = 10;
+    const SECOND: u64 = NANOS_PER_SECOND;
+    const ONE_NEAR: Balance = 10u128.pow(24);
+
+    fn get_context(predecessor_account_id: AccountId) -> VMContextBuilder {
+        let mut builder = VMContextBuilder::new();
+        builder
+            .current_account_id(accounts(0))
+            .signer_account_id(predecessor_account_id.clone())
+            .predecessor_account_id(predecessor_account_id);
+        builder
+    }
+
+    fn nft_contract() -> AccountId {
+        "nft.near".parse().unwrap()
+    }
+
+    fn reward_token() -> AccountId {
+        "reward.near".parse().unwrap()
+    }
+
+    fn other_nft_contract() -> AccountId {
+        "other-nft.near".parse().unwrap()
+    }
+
+    /// Makes storage deposits for `accounts(1)` and `accounts(2)`, enough for all their stakes.
+    fn register_stakers(context: &mut VMContextBuilder, contract: &mut Contract) {
+        for account_id in [accounts(1), accounts(2)].iter() {
+            testing_env!(context
+                .predecessor_account_id(account_id.clone())
+                .attached_deposit(ONE_NEAR)
+                .build());
+            contract.storage_deposit(None, None);
+        }
+        context.attached_deposit(0);
+    }
+
+    /// Creates the contract with a pool for `nft_contract()` and registers the stakers. The
+    /// returned context has the NFT contract as predecessor.
+    fn setup_contract() -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), reward_token(), vec![nft_contract()]);
+        contract.create_pool(nft_contract(), REWARD_RATE.into(), None);
+        register_stakers(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        (context, contract)
+    }
+
+    /// Adds `amount` to the reward reserve as if `accounts(0)` transferred it from the reward
+    /// token.
+    fn fund_reserve(context: &mut VMContextBuilder, contract: &mut Contract, amount: Balance) {
+        testing_env!(context.predecessor_account_id(reward_token()).build());
+        contract.ft_on_transfer(accounts(0), U128(amount), String::new());
+    }
+
+    /// Function calls of the receipts created since the last `testing_env!`, as their receiver,
+    /// method name, JSON arguments, if any, and attached deposit.
+    fn function_calls() -> Vec<(AccountId, String, serde_json::Value, Balance)> {
+        test_utils::get_created_receipts()
+            .into_iter()
+            .flat_map(|receipt| {
+                let receiver_id = receipt.receiver_id;
+                receipt.actions.into_iter().filter_map(move |action| match action {
+                    near_sdk::mock::VmAction::FunctionCall {
+                        function_name, args, deposit, ..
+                    } => Some((
+                        receiver_id.clone(),
+                        function_name,
+                        serde_json::from_slice(&args).unwrap_or(serde_json::Value::Null),
+                        deposit,
+                    )),
+                    _ => None,
+                })
+            })
+            .collect()
+    }
+
+    #[derive(Deserialize)]
+    #[serde(crate = "near_sdk::serde")]
+    struct RewardClaimedArgs {
+        account_id: AccountId,
+        nft_contract: Option<AccountId>,
+        token_id: Option<TokenId>,
+        amount: U128,
+        claimed: Vec<(AccountId, TokenId, U128)>,
+    }
+
+    /// Runs the `on_reward_claimed` callback scheduled by the last claim with the arguments it
+    /// was scheduled with, and `result` as the outcome of the reward transfer. Returns the amount
+    /// paid out.
+    fn resolve_claim(
+        context: &mut VMContextBuilder,
+        contract: &mut Contract,
+        result: PromiseResult,
+    ) -> U128 {
+        let (_, _, args, _) = function_calls()
+            .into_iter()
+            .rev()
+            .find(|(_, method, _, _)| method == "on_reward_claimed")
+            .expect("No claim was scheduled");
+        let args: RewardClaimedArgs = serde_json::from_value(args).unwrap();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![result],
+        );
+        contract.on_reward_claimed(
+            args.account_id,
+            args.nft_contract,
+            args.token_id,
+            args.amount,
+            args.claimed,
+        )
+    }
+
+    /// Creates the contract and stakes token "1" for `accounts(1)` at time zero.
+    fn setup_staked() -> (VMContextBuilder, Contract) {
+        let (context, mut contract) = setup_contract();
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        (context, contract)
+    }
+
+    /// Unlocked stake of `owner_id` in the `nft_contract()` pool, made at time zero and settled
+    /// up to `last_update`.
+    fn stake_fixture(owner_id: AccountId, last_update: u64) -> Stake {
+        Stake {
+            owner_id,
+            nft_contract: nft_contract(),
+            staked_at: 0,
+            last_update,
+            cliff_duration_sec: 0,
+            paused_sec_at_stake: 0,
+            paused_sec_at_update: 0,
+            unlock_at: None,
+            bonus_weight: 0,
+            accrued: 0,
+            base_remainder: 0,
+            bonus_remainder: 0,
+            reward_recipient: None,
+            approval_id: None,
+            lifetime_claimed: 0,
+            unclaimed: 0,
+        }
+    }
+
+    #[test]
+    fn test_stake() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.block_timestamp(1_000).build());
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(false)));
+
+        let stake = contract.get_stake(nft_contract(), "1".to_string()).unwrap();
+        assert_eq!(stake.owner_id, accounts(1));
+        assert_eq!(stake.nft_contract, nft_contract());
+        assert_eq!(stake.staked_at, 1_000);
+        assert_eq!(contract.get_pool(nft_contract()).unwrap().total_staked, 1);
+    }
+
+    #[test]
+    fn test_stake_disallowed_collection() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_stake_collection_without_pool() {
+        let mut context = get_context(nft_contract());
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), reward_token(), vec![nft_contract()]);
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.create_pool(nft_contract(), REWARD_RATE.into(), None);
+        contract.set_pool_enabled(nft_contract(), false);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+    }
+
+    #[test]
+    fn test_pools_accrue_independently() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract =
+            Contract::new(accounts(0), reward_token(), vec![nft_contract(), other_nft_contract()]);
+        contract.create_pool(nft_contract(), U128(10), None);
+        contract.create_pool(other_nft_contract(), U128(25), None);
+        register_stakers(&mut context, &mut contract);
+
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        testing_env!(context.predecessor_account_id(other_nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+
+        testing_env!(context.is_view(true).block_timestamp(10 * SECOND).build());
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "1".to_string()).0, 100);
+        assert_eq!(contract.view_pending_rewards(other_nft_contract(), "2".to_string()).0, 250);
+        assert_eq!(contract.get_pool(other_nft_contract()).unwrap().reward_rate_per_second.0, 25);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_create_pool_not_owner() {
+        let (_context, mut contract) = setup_contract();
+        contract.create_pool(other_nft_contract(), U128(1), None);
+    }
+
+    #[test]
+    fn test_pending_rewards() {
+        let (mut context, contract) = setup_staked();
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "1".to_string()).0, 0);
+
+        testing_env!(context.is_view(true).block_timestamp(100 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            100 * REWARD_RATE
+        );
+
+        // Partial seconds aren't counted yet.
+        testing_env!(context.block_timestamp(100 * SECOND + SECOND / 2).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            100 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    fn test_pending_rewards_long_duration() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let rate: Balance = 10u128.pow(24);
+        let mut contract = Contract::new(accounts(0), reward_token(), vec![nft_contract()]);
+        contract.create_pool(nft_contract(), rate.into(), None);
+        register_stakers(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+
+        let ten_years = 10 * 365 * 24 * 60 * 60;
+        testing_env!(context.block_timestamp(ten_years * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            Balance::from(ten_years) * rate
+        );
+    }
+
+    #[test]
+    fn test_unstake() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(5 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 5 * REWARD_RATE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the staker can unstake")]
+    fn test_unstake_not_staker() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.unstake(nft_contract(), "1".to_string());
+    }
+
+    #[test]
+    fn test_resolve_unstake_failed_transfer() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(5 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+
+        let stake = stake_fixture(accounts(1), 5 * SECOND);
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert!(!contract.resolve_unstake("1".to_string(), stake, U128(5 * REWARD_RATE), false));
+        assert_eq!(
+            contract.get_stake(nft_contract(), "1".to_string()).unwrap().owner_id,
+            accounts(1)
+        );
+        assert_eq!(contract.get_pool(nft_contract()).unwrap().total_staked, 1);
+        // Rewards settled before the transfer are kept.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 5 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_claim_rewards() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+
+        // The whole balance is in flight, and accrual restarts from the claim.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "1".to_string()).0, 0);
+
+        let calls = function_calls();
+        assert_eq!(calls.len(), 2);
+        let (receiver_id, method, args, deposit) = &calls[0];
+        assert_eq!(receiver_id, &reward_token());
+        assert_eq!(method, "ft_transfer");
+        assert_eq!(
+            args,
+            &serde_json::json!({
+                "receiver_id": accounts(1),
+                "amount": U128(7 * REWARD_RATE),
+                "memo": "Staking rewards",
+            })
+        );
+        assert_eq!(*deposit, ONE_YOCTO);
+        assert_eq!(calls[1].0, accounts(0));
+        assert_eq!(calls[1].1, "on_reward_claimed");
+
+        let paid = resolve_claim(&mut context, &mut contract, PromiseResult::Successful(vec![]));
+        assert_eq!(paid.0, 7 * REWARD_RATE);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 7 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_claim_rewards_failed_transfer() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+
+        let paid = resolve_claim(&mut context, &mut contract, PromiseResult::Failed);
+        assert_eq!(paid.0, 0);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 7 * REWARD_RATE);
+        assert_eq!(contract.get_reward_reserve().0, 1_000);
+        assert_eq!(contract.get_account_lifetime_claimed(accounts(1)).0, 0);
+    }
+
+    #[test]
+    fn test_lifetime_claimed() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        for timestamp in [7, 10] {
+            testing_env!(context
+                .predecessor_account_id(accounts(1))
+                .block_timestamp(timestamp * SECOND)
+                .build());
+            contract.claim_rewards(nft_contract(), "1".to_string());
+            resolve_claim(&mut context, &mut contract, PromiseResult::Successful(vec![]));
+        }
+        let stake = contract.get_stake(nft_contract(), "1".to_string()).unwrap();
+        assert_eq!(stake.lifetime_claimed.0, 10 * REWARD_RATE);
+        assert_eq!(contract.get_account_lifetime_claimed(accounts(1)).0, 10 * REWARD_RATE);
+
+        // The account total survives the unstake and counts the rewards credited by it.
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(15 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+        contract.claim_all_rewards(None);
+        resolve_claim(&mut context, &mut contract, PromiseResult::Successful(vec![]));
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_account_lifetime_claimed(accounts(1)).0, 15 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_lifetime_claimed_partial_claims() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.claim_amount(nft_contract(), "1".to_string(), U128(40));
+        resolve_claim(&mut context, &mut contract, PromiseResult::Failed);
+
+        // Later payouts of the rewards settled by the first claim, including the failed share,
+        // are attributed to the stake.
+        for amount in [40, 60] {
+            testing_env!(context.predecessor_account_id(accounts(1)).build());
+            contract.claim_amount(nft_contract(), "1".to_string(), U128(amount));
+            resolve_claim(&mut context, &mut contract, PromiseResult::Successful(vec![]));
+        }
+        let stake = contract.get_stake(nft_contract(), "1".to_string()).unwrap();
+        assert_eq!(stake.lifetime_claimed.0, 10 * REWARD_RATE);
+        assert_eq!(contract.get_account_lifetime_claimed(accounts(1)).0, 10 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_lifetime_claimed_short_reserve() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        resolve_claim(&mut context, &mut contract, PromiseResult::Successful(vec![]));
+
+        // The rest is attributed once the reserve is refilled.
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        resolve_claim(&mut context, &mut contract, PromiseResult::Successful(vec![]));
+        let stake = contract.get_stake(nft_contract(), "1".to_string()).unwrap();
+        assert_eq!(stake.lifetime_claimed.0, 10 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_claim_rewards_partial_reserve() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 30);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_reward_reserve().0, 0);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 7 * REWARD_RATE - 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reward reserve is empty")]
+    fn test_claim_rewards_empty_reserve() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+    }
+
+    #[test]
+    fn test_fund_reserve() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(reward_token()).build());
+        let result = contract.ft_on_transfer(accounts(0), U128(500), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert_eq!(contract.get_reward_reserve().0, 500);
+    }
+
+    #[test]
+    fn test_fund_reserve_foreign_token() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        let result = contract.ft_on_transfer(accounts(0), U128(500), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(U128(500))));
+        assert_eq!(contract.get_reward_reserve().0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the staker can claim rewards")]
+    fn test_claim_rewards_not_staker() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+    }
+
+    /// Stakes token "2" for `accounts(1)` at time zero with a 100 second lock and a 1000 bps
+    /// early unstake penalty.
+    fn setup_locked() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_early_unstake_penalty_bps(1_000);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        let msg = r#"{"lock_duration_sec": 100}"#.to_string();
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), msg);
+        (context, contract)
+    }
+
+    #[test]
+    fn test_unstake_after_lock() {
+        let (mut context, mut contract) = setup_locked();
+        assert_eq!(contract.view_unlock_time(nft_contract(), "2".to_string()), Some(100 * SECOND));
+        assert_eq!(contract.view_unlock_time(nft_contract(), "1".to_string()), None);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(100 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "2".to_string());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 100 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_unstake_before_lock() {
+        let (mut context, mut contract) = setup_locked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(50 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "2".to_string());
+        // 10% of the 500 accrued rewards are forfeited.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 450);
+        assert!(contract.get_stake(nft_contract(), "2".to_string()).is_none());
+    }
+
+    #[test]
+    fn test_relock_after_lock() {
+        let (mut context, mut contract) = setup_locked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(150 * SECOND)
+            .build());
+        contract.relock(nft_contract(), "2".to_string(), 200);
+        // Rewards up to the relock are settled without a penalty, and the stake restarts.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 150 * REWARD_RATE);
+        assert_eq!(contract.view_unlock_time(nft_contract(), "2".to_string()), Some(350 * SECOND));
+        let stake = contract.get_stake(nft_contract(), "2".to_string()).unwrap();
+        assert_eq!(stake.staked_at, 150 * SECOND);
+        assert_eq!(stake.pending_rewards.0, 0);
+
+        // The new lock applies the penalty again.
+        testing_env!(context.block_timestamp(250 * SECOND).build());
+        contract.unstake(nft_contract(), "2".to_string());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 150 * REWARD_RATE + 900);
+    }
+
+    #[test]
+    #[should_panic(expected = "Rewards of a locked stake can't be claimed")]
+    fn test_claim_locked_stake() {
+        let (mut context, mut contract) = setup_locked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(50 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "2".to_string());
+    }
+
+    #[test]
+    fn test_claim_all_before_early_unstake() {
+        let (mut context, mut contract) = setup_locked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(50 * SECOND)
+            .build());
+        // Only the unlocked stake is claimed.
+        assert_eq!(contract.claim_all_rewards(None), 2);
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 50 * REWARD_RATE);
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "2".to_string()).0,
+            50 * REWARD_RATE
+        );
+
+        testing_env!(context.block_timestamp(60 * SECOND).build());
+        contract.unstake(nft_contract(), "2".to_string());
+        // All rewards of the locked stake are slashed by 10%.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 540);
+    }
+
+    #[test]
+    fn test_relock_extends_active_lock() {
+        let (mut context, mut contract) = setup_locked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(50 * SECOND)
+            .build());
+        contract.relock(nft_contract(), "2".to_string(), 51);
+        assert_eq!(contract.view_unlock_time(nft_contract(), "2".to_string()), Some(101 * SECOND));
+        // The stake isn't settled while locked.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "2".to_string()).0,
+            50 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Relocking can't shorten the current lock")]
+    fn test_relock_shortening_lock() {
+        let (mut context, mut contract) = setup_locked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(50 * SECOND)
+            .build());
+        contract.relock(nft_contract(), "2".to_string(), 30);
+    }
+
+    #[test]
+    #[should_panic(expected = "Lock duration is too long")]
+    fn test_relock_overflowing_lock() {
+        let (mut context, mut contract) = setup_locked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(150 * SECOND)
+            .build());
+        contract.relock(nft_contract(), "2".to_string(), u64::MAX / NANOS_PER_SECOND);
+    }
+
+    #[test]
+    fn test_multiplier_doubles_rewards() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_multiplier(nft_contract(), "2".to_string(), 20_000);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(2), accounts(2), "2".to_string(), String::new());
+        assert_eq!(contract.get_multiplier(nft_contract(), "1".to_string()), 10_000);
+        assert_eq!(contract.get_multiplier(nft_contract(), "2".to_string()), 20_000);
+        // The same token id in another collection isn't multiplied.
+        assert_eq!(contract.get_multiplier(other_nft_contract(), "2".to_string()), 10_000);
+
+        testing_env!(context.is_view(true).block_timestamp(30 * SECOND).build());
+        let single = contract.view_pending_rewards(nft_contract(), "1".to_string()).0;
+        assert_eq!(single, 30 * REWARD_RATE);
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "2".to_string()).0, 2 * single);
+    }
+
+    /// Stakes tokens "1" and "2" for `accounts(1)` and token "3" for `accounts(2)` at time zero, in
+    /// a pool with a 1.5x set bonus from two tokens on.
+    fn setup_set_bonus() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_pool_set_bonus(nft_contract(), 5_000, 2);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+        contract.nft_on_transfer(accounts(2), accounts(2), "3".to_string(), String::new());
+        (context, contract)
+    }
+
+    #[test]
+    fn test_set_bonus() {
+        let (mut context, contract) = setup_set_bonus();
+        let pool = contract.get_pool(nft_contract()).unwrap();
+        assert_eq!((pool.set_bonus_bps, pool.set_bonus_threshold), (5_000, 2));
+
+        testing_env!(context.is_view(true).block_timestamp(10 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            15 * REWARD_RATE
+        );
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "2".to_string()).0,
+            15 * REWARD_RATE
+        );
+        // A single stake doesn't reach the threshold.
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "3".to_string()).0,
+            10 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    fn test_set_bonus_removed_on_unstake() {
+        let (mut context, mut contract) = setup_set_bonus();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "2".to_string());
+        // The unstaked token settled with the bonus, the remaining one falls back to the pool rate.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 15 * REWARD_RATE);
+        testing_env!(context.is_view(true).block_timestamp(20 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            20 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Multiplier must be between 10000 and 100000 bps")]
+    fn test_set_multiplier_out_of_range() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_multiplier(nft_contract(), "1".to_string(), 100_001);
+    }
+
+    #[test]
+    fn test_set_multiplier_of_staked_token() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.set_multiplier(nft_contract(), "1".to_string(), 20_000);
+        // Rewards accrued before the change keep the old multiplier.
+        testing_env!(context.block_timestamp(20 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            10 * REWARD_RATE + 20 * REWARD_RATE
+        );
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unstake(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 30 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_get_stakes_by_owner() {
+        let (mut context, mut contract) = setup_staked();
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+        contract.nft_on_transfer(accounts(1), accounts(1), "3".to_string(), String::new());
+        contract.nft_on_transfer(accounts(2), accounts(2), "4".to_string(), String::new());
+
+        testing_env!(context.is_view(true).block_timestamp(10 * SECOND).build());
+        let first_page = contract.get_stakes_by_owner(accounts(1), None, Some(2));
+        let second_page = contract.get_stakes_by_owner(accounts(1), Some(2), Some(2));
+        let token_ids: Vec<TokenId> =
+            first_page.iter().chain(second_page.iter()).map(|view| view.token_id.clone()).collect();
+        assert_eq!(token_ids, vec!["1".to_string(), "2".to_string(), "3".to_string()]);
+        assert_eq!(first_page[0].pending_rewards.0, 10 * REWARD_RATE);
+        assert_eq!(first_page[0].unlock_at, None);
+        assert!(contract.get_stakes_by_owner(accounts(3), None, None).is_empty());
+    }
+
+    #[test]
+    fn test_unstake_updates_stakes_by_owner() {
+        let (mut context, mut contract) = setup_staked();
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unstake(nft_contract(), "1".to_string());
+        let stakes = contract.get_stakes_by_owner(accounts(1), None, None);
+        assert_eq!(stakes.len(), 1);
+        assert_eq!(stakes[0].token_id, "2".to_string());
+    }
+
+    #[test]
+    fn test_emergency_unstake() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(5 * SECOND)
+            .build());
+        contract.emergency_unstake(nft_contract(), "1".to_string());
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_pool(nft_contract()).unwrap().total_staked, 0);
+        // Nothing is logged until the NFT is returned.
+        assert!(test_utils::get_logs().is_empty());
+        let (_, _, args, _) = function_calls()
+            .into_iter()
+            .find(|(_, method, _, _)| method == "resolve_unstake")
+            .unwrap();
+        assert_eq!(args["amount"], "50");
+        assert_eq!(args["emergency"], true);
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let stake = stake_fixture(accounts(1), 0);
+        assert!(contract.resolve_unstake("1".to_string(), stake, U128(5 * REWARD_RATE), true));
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(
+            test_utils::get_logs(),
+            vec![concat!(
+                r#"EVENT_JSON:{"standard":"nft_staking","version":"1.0.0","#,
+                r#""event":"emergency_unstake","data":[{"account_id":"bob","token_id":"1","#,
+                r#""nft_contract":"nft.near","amount":"50"}]}"#
+            )]
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the staker can unstake")]
+    fn test_emergency_unstake_not_staker() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.emergency_unstake(nft_contract(), "1".to_string());
+    }
+
+    #[test]
+    fn test_compound() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(100 * SECOND)
+            .build());
+        let result = contract.compound(nft_contract(), "1".to_string());
+        assert!(
+            matches!(result, PromiseOrValue::Value(U128(amount)) if amount == 100 * REWARD_RATE)
+        );
+        // Compounding again in the same second has nothing to add.
+        let result = contract.compound(nft_contract(), "1".to_string());
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert_eq!(
+            contract.get_stake(nft_contract(), "1".to_string()).unwrap().bonus_weight.0,
+            100 * REWARD_RATE
+        );
+
+        // Token "2" starts accruing when token "1" was compounded, so they only differ by the
+        // bonus.
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+        let year = SECONDS_PER_YEAR * SECOND;
+        testing_env!(context.is_view(true).block_timestamp(100 * SECOND + year).build());
+        let plain = contract.view_pending_rewards(nft_contract(), "2".to_string()).0;
+        let compounded = contract.view_pending_rewards(nft_contract(), "1".to_string()).0;
+        assert_eq!(plain, Balance::from(SECONDS_PER_YEAR) * REWARD_RATE);
+        // 10% a year of the 1000 compounded rewards.
+        assert_eq!(compounded - plain, 100);
+    }
+
+    #[test]
+    fn test_compound_credited_on_unstake() {
+        let (mut context, mut contract) = setup_locked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(100 * SECOND)
+            .build());
+        contract.compound(nft_contract(), "2".to_string());
+        contract.relock(nft_contract(), "2".to_string(), 100);
+        testing_env!(context.block_timestamp(110 * SECOND).build());
+        contract.unstake(nft_contract(), "2".to_string());
+        // Both the 100 rewards settled after compounding and the 1000 compounded ones are slashed
+        // by 10%.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 90 + 900);
+    }
+
+    #[test]
+    #[should_panic(expected = "Rewards of a locked stake can't be compounded")]
+    fn test_compound_locked_stake() {
+        let (mut context, mut contract) = setup_locked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(50 * SECOND)
+            .build());
+        contract.compound(nft_contract(), "2".to_string());
+    }
+
+    #[test]
+    fn test_compound_forfeited_on_emergency_unstake() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(100 * SECOND)
+            .build());
+        contract.compound(nft_contract(), "1".to_string());
+        testing_env!(context.block_timestamp(110 * SECOND).build());
+        contract.emergency_unstake(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        let (_, _, args, _) = function_calls()
+            .into_iter()
+            .find(|(_, method, _, _)| method == "resolve_unstake")
+            .unwrap();
+        assert_eq!(args["amount"], (110 * REWARD_RATE).to_string());
+        assert_eq!(args["stake"]["bonus_weight"], 0);
+    }
+
+    #[test]
+    fn test_emergency_unstake_failed_transfer() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(5 * SECOND)
+            .build());
+        contract.emergency_unstake(nft_contract(), "1".to_string());
+        let (_, _, args, _) = function_calls()
+            .into_iter()
+            .find(|(_, method, _, _)| method == "resolve_unstake")
+            .unwrap();
+        let stake: Stake = serde_json::from_value(args["stake"].clone()).unwrap();
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        assert!(!contract.resolve_unstake("1".to_string(), stake, U128(5 * REWARD_RATE), true));
+        // The restored stake doesn't accrue the forfeited rewards again.
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "1".to_string()).0, 0);
+        testing_env!(context.block_timestamp(8 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            3 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    fn test_halving_rewards_periods() {
+        // 100 seconds at 8, 100 at 4 and 50 at 2.
+        assert_eq!(rewards::halving_rewards(8, 0, 100, 0, 250), Some(1_300));
+        assert_eq!(rewards::halving_rewards(8, 0, 0, 0, 250), Some(2_000));
+        // The rate is zero after enough halvings.
+        assert_eq!(rewards::halving_rewards(8, 0, 100, 400, 10_000), Some(0));
+    }
+
+    #[test]
+    fn test_pending_rewards_across_halving() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), reward_token(), vec![nft_contract()]);
+        contract.create_pool(nft_contract(), REWARD_RATE.into(), Some(100));
+        register_stakers(&mut context, &mut contract);
+        testing_env!(context
+            .predecessor_account_id(nft_contract())
+            .block_timestamp(50 * SECOND)
+            .build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+
+        testing_env!(context.is_view(true).block_timestamp(150 * SECOND).build());
+        // 50 seconds before the halving at the full rate and 50 after it at half the rate.
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            50 * REWARD_RATE + 50 * REWARD_RATE / 2
+        );
+    }
+
+    #[test]
+    fn test_allowed_nft_contracts() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.add_allowed_nft_contract(other_nft_contract());
+        assert_eq!(
+            contract.get_allowed_nft_contracts(),
+            vec![nft_contract(), other_nft_contract()]
+        );
+        contract.remove_allowed_nft_contract(nft_contract());
+        assert_eq!(contract.get_allowed_nft_contracts(), vec![other_nft_contract()]);
+
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+    }
+
+    #[test]
+    fn test_unstake_removed_collection() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.remove_allowed_nft_contract(nft_contract());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(5 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 5 * REWARD_RATE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_add_allowed_nft_contract_not_owner() {
+        let (_context, mut contract) = setup_contract();
+        contract.add_allowed_nft_contract(other_nft_contract());
+    }
+
+    #[test]
+    fn test_total_staked() {
+        let (mut context, mut contract) = setup_staked();
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+        assert_eq!(contract.get_total_staked(nft_contract()), 2);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unstake(nft_contract(), "1".to_string());
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let stake = stake_fixture(accounts(1), 0);
+        contract.resolve_unstake("1".to_string(), stake, U128(0), false);
+        assert_eq!(contract.get_total_staked(nft_contract()), 1);
+        assert_eq!(contract.get_total_staked(other_nft_contract()), 0);
+
+        let pools = contract.get_all_pools();
+        assert_eq!(pools.len(), 1);
+        assert_eq!(pools[0].nft_contract, nft_contract());
+        assert_eq!(pools[0].reward_rate_per_second.0, REWARD_RATE);
+        assert_eq!(pools[0].total_staked, 1);
+    }
+
+    #[test]
+    fn test_staking_stats() {
+        let (mut context, mut contract) = setup_staked();
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+        contract.nft_on_transfer(accounts(2), accounts(2), "3".to_string(), String::new());
+        fund_reserve(&mut context, &mut contract, 1_000);
+        let stats = contract.get_staking_stats();
+        assert_eq!((stats.total_stakes, stats.total_stakers, stats.total_pools), (3, 2, 1));
+        assert_eq!(stats.reward_reserve.0, 1_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(2))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "3".to_string());
+        let stats = contract.get_staking_stats();
+        assert_eq!((stats.total_stakes, stats.total_stakers), (2, 1));
+
+        // A failed NFT transfer restores the stake and its staker.
+        let stake = stake_fixture(accounts(2), 10 * SECOND);
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.resolve_unstake("3".to_string(), stake, U128(10 * REWARD_RATE), false);
+        let stats = contract.get_staking_stats();
+        assert_eq!((stats.total_stakes, stats.total_stakers), (3, 2));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        contract.unstake(nft_contract(), "2".to_string());
+        let stats = contract.get_staking_stats();
+        assert_eq!((stats.total_stakes, stats.total_stakers), (2, 2));
+        assert_eq!(stats.reward_reserve.0, 1_000 - 10 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_claim_amount() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_multiplier(nft_contract(), "1".to_string(), 15_000);
+        fund_reserve(&mut context, &mut contract, 1_000);
+
+        // 1.5x for 7 seconds accrues 105, claimed in two uneven parts.
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.claim_amount(nft_contract(), "1".to_string(), U128(52));
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 53);
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 52);
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "1".to_string()).0, 0);
+
+        contract.claim_amount(nft_contract(), "1".to_string(), U128(53));
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 105);
+    }
+
+    #[test]
+    #[should_panic(expected = "The amount exceeds the claimable balance")]
+    fn test_claim_amount_over_balance() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(5 * SECOND)
+            .build());
+        contract.claim_amount(nft_contract(), "1".to_string(), U128(5 * REWARD_RATE + 1));
+    }
+
+    #[test]
+    fn test_emission_cap() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_emission_cap(U128(80), 100);
+        fund_reserve(&mut context, &mut contract, 1_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        // 20 of the 100 accrued rewards are deferred to the next epoch.
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 80);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 20);
+        assert_eq!(contract.get_emission_budget(), Some(U128(0)));
+
+        testing_env!(context.block_timestamp(100 * SECOND).build());
+        assert_eq!(contract.get_emission_budget(), Some(U128(80)));
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        // 20 deferred and 80 of the 900 accrued since the first claim.
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 160);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 840);
+    }
+
+    #[test]
+    #[should_panic(expected = "The emission budget of this epoch is exhausted")]
+    fn test_emission_cap_exhausted() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_emission_cap(U128(80), 100);
+        fund_reserve(&mut context, &mut contract, 1_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        testing_env!(context.block_timestamp(99 * SECOND).build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Pending rewards below minimum claim threshold")]
+    fn test_claim_rewards_below_threshold() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_min_claim_amount(U128(100));
+        assert_eq!(contract.get_min_claim_amount().0, 100);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(5 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+    }
+
+    #[test]
+    fn test_claim_rewards_carries_remainder() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), reward_token(), vec![nft_contract()]);
+        contract.create_pool(nft_contract(), U128(1), None);
+        contract.set_multiplier(nft_contract(), "1".to_string(), 15_000);
+        register_stakers(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        fund_reserve(&mut context, &mut contract, 100);
+
+        // 1.5 rewards a second: the half left over from the first claim is paid by the second.
+        testing_env!(context.predecessor_account_id(accounts(1)).block_timestamp(SECOND).build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_reward_reserve().0, 99);
+        testing_env!(context.block_timestamp(2 * SECOND).build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_reward_reserve().0, 97);
+    }
+
+    #[test]
+    fn test_claim_rewards_to_recipient() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_reward_recipient(nft_contract(), "1".to_string(), accounts(2));
+        assert_eq!(
+            contract.get_stake(nft_contract(), "1".to_string()).unwrap().reward_recipient,
+            Some(accounts(2))
+        );
+
+        testing_env!(context.block_timestamp(7 * SECOND).build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 7 * REWARD_RATE);
+
+        // A failed payout is credited back to the recipient, not the staker.
+        resolve_claim(&mut context, &mut contract, PromiseResult::Failed);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(2)).0, 7 * REWARD_RATE);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+    }
+
+    #[test]
+    fn test_change_reward_recipient() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_reward_recipient(nft_contract(), "1".to_string(), accounts(2));
+        testing_env!(context.block_timestamp(5 * SECOND).build());
+        contract.set_reward_recipient(nft_contract(), "1".to_string(), accounts(1));
+        // Rewards accrued under the delegation are credited to the previous recipient, and stay
+        // counted as unclaimed rewards of the stake.
+        assert_eq!(contract.get_unclaimed_rewards(accounts(2)).0, 5 * REWARD_RATE);
+        let key = (nft_contract(), "1".to_string());
+        assert_eq!(contract.stakes.get(&key).unwrap().unclaimed, 5 * REWARD_RATE);
+
+        testing_env!(context.block_timestamp(7 * SECOND).build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(2)).0, 5 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_unstake_with_recipient() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_reward_recipient(nft_contract(), "1".to_string(), accounts(2));
+        testing_env!(context.block_timestamp(5 * SECOND).build());
+        contract.unstake(nft_contract(), "1".to_string());
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(2)).0, 5 * REWARD_RATE);
+    }
+
+    #[test]
+    fn test_relock_with_recipient() {
+        let (mut context, mut contract) = setup_locked();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_reward_recipient(nft_contract(), "2".to_string(), accounts(2));
+        testing_env!(context.block_timestamp(150 * SECOND).build());
+        contract.relock(nft_contract(), "2".to_string(), 200);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(2)).0, 150 * REWARD_RATE);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the staker can unstake")]
+    fn test_unstake_by_recipient() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_reward_recipient(nft_contract(), "1".to_string(), accounts(2));
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.unstake(nft_contract(), "1".to_string());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the staker can set the reward recipient")]
+    fn test_set_reward_recipient_not_staker() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.set_reward_recipient(nft_contract(), "1".to_string(), accounts(2));
+    }
+
+    #[test]
+    fn test_claim_all_rewards() {
+        let (mut context, mut contract) = setup_staked();
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+        contract.nft_on_transfer(accounts(1), accounts(1), "3".to_string(), String::new());
+        fund_reserve(&mut context, &mut contract, 1_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        assert_eq!(contract.claim_all_rewards(None), 3);
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 3 * 10 * REWARD_RATE);
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "3".to_string()).0, 0);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+
+        // The summed amount is restored if the transfer fails.
+        resolve_claim(&mut context, &mut contract, PromiseResult::Failed);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 3 * 10 * REWARD_RATE);
+        assert_eq!(contract.get_reward_reserve().0, 1_000);
+    }
+
+    #[test]
+    fn test_claim_all_rewards_from_index() {
+        let (mut context, mut contract) = setup_staked();
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+        fund_reserve(&mut context, &mut contract, 1_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        assert_eq!(contract.claim_all_rewards(Some(1)), 1);
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            10 * REWARD_RATE
+        );
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "2".to_string()).0, 0);
+    }
+
+    #[test]
+    fn test_stake_event() {
+        let (_context, mut contract) = setup_contract();
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        assert_eq!(
+            test_utils::get_logs(),
+            vec![concat!(
+                r#"EVENT_JSON:{"standard":"nft_staking","version":"1.0.0","event":"stake","#,
+                r#""data":[{"account_id":"bob","token_id":"1","nft_contract":"nft.near"}]}"#
+            )]
+        );
+    }
+
+    #[test]
+    fn test_claim_event() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        // Nothing is logged until the transfer succeeds.
+        assert!(test_utils::get_logs().is_empty());
+
+        resolve_claim(&mut context, &mut contract, PromiseResult::Successful(vec![]));
+        assert_eq!(
+            test_utils::get_logs(),
+            vec![concat!(
+                r#"EVENT_JSON:{"standard":"nft_staking","version":"1.0.0","event":"claim","#,
+                r#""data":[{"account_id":"bob","nft_contract":"nft.near","token_id":"1","#,
+                r#""amount":"70"}]}"#
+            )]
+        );
+    }
+
+    #[test]
+    fn test_claim_event_failed_transfer() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+
+        resolve_claim(&mut context, &mut contract, PromiseResult::Failed);
+        assert_eq!(
+            test_utils::get_logs(),
+            vec!["Failed to pay 70 rewards to @bob, crediting them back"]
+        );
+    }
+
+    #[test]
+    fn test_unstake_event() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(7 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+        // Nothing is logged until the NFT is returned.
+        assert!(test_utils::get_logs().is_empty());
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Successful(vec![])],
+        );
+        let stake = stake_fixture(accounts(1), 7 * SECOND);
+        contract.resolve_unstake("1".to_string(), stake, U128(7 * REWARD_RATE), false);
+        assert_eq!(
+            test_utils::get_logs(),
+            vec![concat!(
+                r#"EVENT_JSON:{"standard":"nft_staking","version":"1.0.0","event":"unstake","#,
+                r#""data":[{"account_id":"bob","token_id":"1","nft_contract":"nft.near","#,
+                r#""amount":"70"}]}"#
+            )]
+        );
+    }
+
+    #[test]
+    fn test_unstake_event_failed_transfer() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(5 * SECOND)
+            .build());
+        contract.emergency_unstake(nft_contract(), "1".to_string());
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let stake = stake_fixture(accounts(1), 0);
+        contract.resolve_unstake("1".to_string(), stake, U128(5 * REWARD_RATE), true);
+        assert_eq!(test_utils::get_logs(), vec!["Failed to return token 1, restoring the stake"]);
+    }
+
+    #[test]
+    fn test_pending_rewards_cliff() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_pool_cliff(nft_contract(), 60);
+        assert_eq!(contract.get_pool(nft_contract()).unwrap().cliff_duration_sec, 60);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+
+        testing_env!(context.is_view(true).block_timestamp(60 * SECOND).build());
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "1".to_string()).0, 0);
+        // Only the 40 seconds after the cliff are paid.
+        testing_env!(context.block_timestamp(100 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            40 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    fn test_pool_paused() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.set_pool_paused(nft_contract(), true);
+        assert!(contract.get_pool(nft_contract()).unwrap().paused);
+
+        // The 10 seconds before the pause are kept, and nothing accrues while paused.
+        testing_env!(context.block_timestamp(30 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            10 * REWARD_RATE
+        );
+        contract.set_pool_paused(nft_contract(), false);
+        assert!(!contract.get_pool(nft_contract()).unwrap().paused);
+
+        // Accrual resumes from the unpause only.
+        testing_env!(context.block_timestamp(45 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            25 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    fn test_pool_paused_settled_during_pause() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.set_pool_paused(nft_contract(), true);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(20 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 10 * REWARD_RATE);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(30 * SECOND)
+            .build());
+        contract.set_pool_paused(nft_contract(), false);
+        // Neither the paused interval nor the rewards claimed before it are counted again.
+        testing_env!(context.block_timestamp(35 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            5 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    fn test_pool_paused_delays_halving() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), reward_token(), vec![nft_contract()]);
+        contract.create_pool(nft_contract(), REWARD_RATE.into(), Some(100));
+        register_stakers(&mut context, &mut contract);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+
+        for &(timestamp, paused) in &[(50, true), (150, false)] {
+            testing_env!(context
+                .predecessor_account_id(accounts(0))
+                .block_timestamp(timestamp * SECOND)
+                .build());
+            contract.set_pool_paused(nft_contract(), paused);
+        }
+        // The 100 seconds of the pool's clock at 200 are all before the first halving.
+        testing_env!(context.block_timestamp(200 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            100 * REWARD_RATE
+        );
+        testing_env!(context.block_timestamp(300 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            100 * REWARD_RATE + 100 * REWARD_RATE / 2
+        );
+    }
+
+    #[test]
+    fn test_set_pool_cliff_keeps_running_stakes() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(0))
+            .block_timestamp(30 * SECOND)
+            .build());
+        contract.set_pool_cliff(nft_contract(), 60);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), String::new());
+
+        // Only the stake made after the change has a cliff.
+        testing_env!(context.is_view(true).block_timestamp(60 * SECOND).build());
+        assert_eq!(
+            contract.view_pending_rewards(nft_contract(), "1".to_string()).0,
+            60 * REWARD_RATE
+        );
+        assert_eq!(contract.view_pending_rewards(nft_contract(), "2".to_string()).0, 0);
+    }
+
+    #[test]
+    fn test_unstake_during_cliff() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_pool_cliff(nft_contract(), 60);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(30 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+    }
+
+    #[test]
+    fn test_max_stakes_per_account() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_max_stakes_per_account(2);
+        assert_eq!(contract.get_max_stakes_per_account(), 2);
+
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        for token_id in ["1", "2"].iter() {
+            let result = contract.nft_on_transfer(
+                accounts(1),
+                accounts(1),
+                token_id.to_string(),
+                String::new(),
+            );
+            assert!(matches!(result, PromiseOrValue::Value(false)));
+        }
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "3".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+        assert!(contract.get_stake(nft_contract(), "3".to_string()).is_none());
+
+        // Unstaking frees up a slot.
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unstake(nft_contract(), "1".to_string());
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "3".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(false)));
+    }
+
+    #[test]
+    #[should_panic(expected = "The account is not registered, call storage_deposit first")]
+    fn test_stake_unregistered() {
+        let (_context, mut contract) = setup_contract();
+        contract.nft_on_transfer(accounts(3), accounts(3), "1".to_string(), String::new());
+    }
+
+    #[test]
+    #[should_panic(expected = "Insufficient storage deposit")]
+    fn test_stake_insufficient_storage() {
+        let (mut context, mut contract) = setup_contract();
+        let min_balance = contract.storage_balance_bounds().min.0;
+        testing_env!(context
+            .predecessor_account_id(accounts(3))
+            .attached_deposit(min_balance)
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context.predecessor_account_id(nft_contract()).attached_deposit(0).build());
+        contract.nft_on_transfer(accounts(3), accounts(3), "1".to_string(), String::new());
+    }
+
+    #[test]
+    fn test_unstake_releases_storage() {
+        let (mut context, mut contract) = setup_contract();
+        let available = contract.storage_balance_of(accounts(1)).unwrap().available.0;
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        let staked_available = contract.storage_balance_of(accounts(1)).unwrap().available.0;
+        assert!(staked_available < available);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.unstake(nft_contract(), "1".to_string());
+        let balance = contract.storage_balance_of(accounts(1)).unwrap();
+        assert_eq!(balance.available.0, available);
+        assert_eq!(balance.total.0, ONE_NEAR);
+    }
+
+    #[test]
+    fn test_unclaimed_rewards_storage() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        let available = contract.storage_balance_of(accounts(2)).unwrap().available.0;
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_reward_recipient(nft_contract(), "1".to_string(), accounts(2));
+        testing_env!(context.block_timestamp(5 * SECOND).build());
+        contract.unstake(nft_contract(), "1".to_string());
+        // The recipient pays for the storage of its unclaimed balance until it is paid out.
+        assert!(contract.storage_balance_of(accounts(2)).unwrap().available.0 < available);
+
+        testing_env!(context.predecessor_account_id(accounts(2)).build());
+        contract.claim_all_rewards(None);
+        assert_eq!(contract.storage_balance_of(accounts(2)).unwrap().available.0, available);
+    }
+
+    #[test]
+    #[should_panic(expected = "The reward recipient is not registered")]
+    fn test_set_unregistered_reward_recipient() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_reward_recipient(nft_contract(), "1".to_string(), accounts(3));
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister the account with active stakes")]
+    fn test_storage_unregister_with_stakes() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister the account with unclaimed rewards")]
+    fn test_storage_unregister_with_unclaimed_rewards() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+        testing_env!(context.attached_deposit(1).build());
+        contract.storage_unregister(None);
+    }
+
+    #[test]
+    fn test_storage_unregister_forfeiting_unclaimed_rewards() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_min_claim_amount(U128(500));
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+
+        // The unclaimed balance is below the minimum claim amount, so it can only be forfeited.
+        testing_env!(context.attached_deposit(1).build());
+        assert!(contract.storage_unregister(Some(true)));
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_reward_reserve().0, 1_000);
+        assert_eq!(test_utils::get_logs(), vec!["Forfeited 100 unclaimed rewards of @bob"]);
+    }
+
+    #[test]
+    fn test_storage_unregister_after_withdrawing() {
+        let (mut context, mut contract) = setup_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+        contract.claim_all_rewards(None);
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+
+        testing_env!(context.attached_deposit(1).build());
+        assert!(contract.storage_unregister(None));
+        assert!(contract.storage_balance_of(accounts(1)).is_none());
+        // Unregistering again is a no-op.
+        assert!(!contract.storage_unregister(None));
+    }
+
+    #[test]
+    fn test_eligible_token_ids() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_eligible_token_ids(nft_contract(), vec!["1".to_string(), "2".to_string()]);
+        assert_eq!(
+            contract.get_eligible_token_ids(nft_contract()),
+            Some(vec!["1".to_string(), "2".to_string()])
+        );
+
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(false)));
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "3".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+        assert!(contract.get_stake(nft_contract(), "3".to_string()).is_none());
+
+        // Clearing the list makes the whole collection eligible again.
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_eligible_token_ids(nft_contract(), vec![]);
+        assert_eq!(contract.get_eligible_token_ids(nft_contract()), None);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "3".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(false)));
+    }
+
+    #[test]
+    fn test_project_rewards_across_halving() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new(accounts(0), reward_token(), vec![nft_contract()]);
+        contract.create_pool(nft_contract(), REWARD_RATE.into(), Some(100));
+        register_stakers(&mut context, &mut contract);
+        testing_env!(context
+            .predecessor_account_id(nft_contract())
+            .block_timestamp(50 * SECOND)
+            .build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        fund_reserve(&mut context, &mut contract, 10_000);
+
+        let projected = contract.project_rewards(nft_contract(), "1".to_string(), 150).0;
+        assert_eq!(projected, 50 * REWARD_RATE + 50 * REWARD_RATE / 2);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(150 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_reward_reserve().0, 10_000 - projected);
+    }
+
+    #[test]
+    fn test_project_rewards_across_cliff() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(0)).build());
+        contract.set_pool_cliff(nft_contract(), 60);
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        contract.nft_on_transfer(accounts(1), accounts(1), "1".to_string(), String::new());
+        fund_reserve(&mut context, &mut contract, 10_000);
+
+        assert_eq!(contract.project_rewards(nft_contract(), "1".to_string(), 60).0, 0);
+        let projected = contract.project_rewards(nft_contract(), "1".to_string(), 100).0;
+        assert_eq!(projected, 40 * REWARD_RATE);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(100 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        assert_eq!(contract.get_reward_reserve().0, 10_000 - projected);
+    }
+
+    #[test]
+    fn test_project_rewards_before_unlock() {
+        let (_context, contract) = setup_locked();
+        // 10% of the 500 rewards accrued by then would be forfeited.
+        assert_eq!(contract.project_rewards(nft_contract(), "2".to_string(), 50).0, 450);
+        assert_eq!(
+            contract.project_rewards(nft_contract(), "2".to_string(), 100).0,
+            100 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Timestamp is before the last settlement of the stake")]
+    fn test_project_rewards_in_the_past() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(100 * SECOND)
+            .build());
+        contract.compound(nft_contract(), "1".to_string());
+        contract.project_rewards(nft_contract(), "1".to_string(), 50);
+    }
+
+    #[test]
+    fn test_stake_invalid_msg() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context.predecessor_account_id(nft_contract()).build());
+        let result =
+            contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), "lock".to_string());
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+    }
+
+    #[test]
+    fn test_stake_overflowing_lock() {
+        let (mut context, mut contract) = setup_staked();
+        testing_env!(context
+            .predecessor_account_id(nft_contract())
+            .block_timestamp(10 * SECOND)
+            .build());
+        let msg = format!(r#"{{"lock_duration_sec": {}}}"#, u64::MAX / NANOS_PER_SECOND);
+        let result = contract.nft_on_transfer(accounts(1), accounts(1), "2".to_string(), msg);
+        assert!(matches!(result, PromiseOrValue::Value(true)));
+        assert!(contract.get_stake(nft_contract(), "2".to_string()).is_none());
+    }
+
+    /// Result of an `nft_token` call for token "1" owned by `owner_id`, which approved this
+    /// contract with approval id 1.
+    fn nft_token_result(owner_id: AccountId) -> PromiseResult {
+        nft_token_result_with_approvals(owner_id, vec![(accounts(0), 1)].into_iter().collect())
+    }
+
+    fn nft_token_result_with_approvals(
+        owner_id: AccountId,
+        approvals: HashMap<AccountId, u64>,
+    ) -> PromiseResult {
+        let token = serde_json::json!({
+            "token_id": "1",
+            "owner_id": owner_id,
+            "approved_account_ids": approvals,
+        });
+        PromiseResult::Successful(serde_json::to_vec(&token).unwrap())
+    }
+
+    /// Stakes token "1" for `accounts(1)` by approval at time zero. The returned context has the
+    /// contract itself as predecessor.
+    fn setup_approval_staked() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.stake_with_approval(nft_contract(), "1".to_string(), 1);
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![nft_token_result(accounts(1))],
+        );
+        contract.resolve_approval_stake(accounts(1), nft_contract(), "1".to_string(), 1);
+        (context, contract)
+    }
+
+    fn ft_metadata_result(decimals: u8) -> PromiseResult {
+        let metadata = serde_json::json!({
+            "spec": "ft-1.0.0",
+            "name": "Reward",
+            "symbol": "RWD",
+            "decimals": decimals,
+        });
+        PromiseResult::Successful(serde_json::to_vec(&metadata).unwrap())
+    }
+
+    #[test]
+    fn test_reward_token_decimals() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![ft_metadata_result(18)],
+        );
+        contract.on_reward_token_metadata();
+        assert_eq!(contract.get_reward_token_decimals(), 18);
+
+        // A failed refresh keeps the cached value, a successful one replaces it.
+        contract.refresh_reward_token_decimals();
+        testing_env!(
+            context.build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        contract.on_reward_token_metadata();
+        assert_eq!(contract.get_reward_token_decimals(), 18);
+        testing_env!(
+            context.build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![ft_metadata_result(24)],
+        );
+        contract.on_reward_token_metadata();
+        assert_eq!(contract.get_reward_token_decimals(), 24);
+    }
+
+    #[test]
+    #[should_panic(expected = "Reward token decimals haven't been fetched yet")]
+    fn test_reward_token_decimals_not_fetched() {
+        let (_context, contract) = setup_contract();
+        contract.get_reward_token_decimals();
+    }
+
+    #[test]
+    fn test_stake_with_approval() {
+        let (mut context, mut contract) = setup_approval_staked();
+        assert_eq!(
+            contract.get_stake(nft_contract(), "1".to_string()).unwrap().approval_id,
+            Some(1)
+        );
+        assert_eq!(contract.get_total_staked(nft_contract()), 1);
+        fund_reserve(&mut context, &mut contract, 1_000);
+
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+        // Nothing is settled before the NFT contract confirms that the staker holds the token.
+        assert_eq!(contract.get_reward_reserve().0, 1_000);
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![nft_token_result(accounts(1))],
+        );
+        let result = contract.resolve_approval_claim(nft_contract(), "1".to_string(), None);
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        assert_eq!(contract.get_reward_reserve().0, 1_000 - 10 * REWARD_RATE);
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "Token is not owned by the staker")]
+    fn test_stake_with_approval_not_owner() {
+        let (mut context, mut contract) = setup_contract();
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![nft_token_result(accounts(2))],
+        );
+        contract.resolve_approval_stake(accounts(1), nft_contract(), "1".to_string(), 1);
+    }
+
+    #[test]
+    fn test_approval_stake_kept_for_other_collection() {
+        let (mut context, mut contract) = setup_approval_staked();
+        contract.add_allowed_nft_contract(other_nft_contract());
+        contract.create_pool(other_nft_contract(), REWARD_RATE.into(), None);
+
+        // A token with the same id in another collection is staked next to the stake by approval
+        // instead of replacing it.
+        testing_env!(context
+            .predecessor_account_id(other_nft_contract())
+            .block_timestamp(10 * SECOND)
+            .build());
+        let result =
+            contract.nft_on_transfer(accounts(2), accounts(2), "1".to_string(), String::new());
+        assert!(matches!(result, PromiseOrValue::Value(false)));
+        let stake = contract.get_stake(nft_contract(), "1".to_string()).unwrap();
+        assert_eq!(stake.owner_id, accounts(1));
+        assert_eq!(stake.pending_rewards.0, 10 * REWARD_RATE);
+        let other_stake = contract.get_stake(other_nft_contract(), "1".to_string()).unwrap();
+        assert_eq!(other_stake.owner_id, accounts(2));
+        assert_eq!(contract.get_total_staked(nft_contract()), 1);
+        assert_eq!(contract.get_total_staked(other_nft_contract()), 1);
+    }
+
+    #[test]
+    fn test_compound_with_approval() {
+        let (mut context, mut contract) = setup_approval_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        let result = contract.compound(nft_contract(), "1".to_string());
+        assert!(matches!(result, PromiseOrValue::Promise(_)));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![nft_token_result(accounts(1))],
+        );
+        let compounded = contract.resolve_approval_compound(nft_contract(), "1".to_string());
+        assert_eq!(compounded.0, 10 * REWARD_RATE);
+        assert_eq!(
+            contract.get_stake(nft_contract(), "1".to_string()).unwrap().bonus_weight.0,
+            10 * REWARD_RATE
+        );
+    }
+
+    #[test]
+    fn test_approval_stake_cancelled_after_revoke() {
+        let (mut context, mut contract) = setup_approval_staked();
+        fund_reserve(&mut context, &mut contract, 1_000);
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.claim_rewards(nft_contract(), "1".to_string());
+
+        // The staker still holds the NFT, but revoked the approval of the staking contract.
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![nft_token_result_with_approvals(accounts(1), HashMap::new())],
+        );
+        let result = contract.resolve_approval_claim(nft_contract(), "1".to_string(), None);
+        assert!(matches!(result, PromiseOrValue::Value(U128(0))));
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_reward_reserve().0, 1_000);
+    }
+
+    #[test]
+    fn test_approval_stake_cancelled_after_transfer() {
+        let (mut context, mut contract) = setup_approval_staked();
+        testing_env!(context
+            .predecessor_account_id(accounts(1))
+            .block_timestamp(10 * SECOND)
+            .build());
+        contract.unstake(nft_contract(), "1".to_string());
+
+        // The staker transferred the NFT away in the meantime.
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![nft_token_result(accounts(2))],
+        );
+        assert!(!contract.resolve_approval_unstake(nft_contract(), "1".to_string()));
+        assert!(contract.get_stake(nft_contract(), "1".to_string()).is_none());
+        assert_eq!(contract.get_unclaimed_rewards(accounts(1)).0, 0);
+        assert_eq!(contract.get_total_staked(nft_contract()), 0);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::*;
+
+/// Reward configuration of a staked NFT collection.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct Pool {
+    /// Rewards per second of each stake, in the smallest unit of the reward token, i.e. scaled by
+    /// its `decimals`.
+    pub reward_rate_per_second: Balance,
+    /// Number of tokens of the collection that are currently staked.
+    pub total_staked: u64,
+    /// Whether the pool accepts new stakes.
+    pub enabled: bool,
+    /// Block timestamp the halving schedule starts from, in nanoseconds.
+    pub genesis_timestamp: u64,
+    /// Seconds after which the reward rate halves. Zero means the rate never halves. Paused time
+    /// doesn't count.
+    pub halving_interval_sec: u64,
+    /// Seconds after staking during which a stake earns nothing, for stakes made from now on.
+    /// Paused time doesn't count.
+    pub cliff_duration_sec: u64,
+    /// Extra reward rate, in basis points, of all stakes of an account in this collection while
+    /// it has at least `set_bonus_threshold` of them staked. Zero disables the bonus.
+    pub set_bonus_bps: u32,
+    pub set_bonus_threshold: u32,
+    /// Seconds the pool has been paused for, not counting an ongoing pause.
+    pub paused_sec: u64,
+    /// Start of the ongoing pause, in seconds.
+    pub paused_at: Option<u64>,
+}
+
+impl Pool {
+    /// Whether accrual is currently paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Seconds the pool will have been paused for at `timestamp_sec`, assuming it isn't paused or
+    /// unpaused until then. Stakes measure their accrual on the pool's clock, which is the time
+    /// in seconds minus these paused seconds.
+    pub fn paused_sec_at(&self, timestamp_sec: u64) -> u64 {
+        self.paused_sec
+            + self.paused_at.map_or(0, |paused_at| timestamp_sec.saturating_sub(paused_at))
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "near_sdk::serde")]
+pub struct PoolView {
+    pub nft_contract: AccountId,
+    pub reward_rate_per_second: U128,
+    pub total_staked: u64,
+    pub enabled: bool,
+    pub genesis_timestamp: u64,
+    pub halving_interval_sec: u64,
+    pub cliff_duration_sec: u64,
+    pub set_bonus_bps: u32,
+    pub set_bonus_threshold: u32,
+    pub paused: bool,
+}
+
+impl PoolView {
+    pub fn new(nft_contract: AccountId, pool: Pool) -> Self {
+        Self {
+            nft_contract,
+            reward_rate_per_second: pool.reward_rate_per_second.into(),
+            total_staked: pool.total_staked,
+            enabled: pool.enabled,
+            genesis_timestamp: pool.genesis_timestamp,
+            halving_interval_sec: pool.halving_interval_sec,
+            cliff_duration_sec: pool.cliff_duration_sec,
+            set_bonus_bps: pool.set_bonus_bps,
+            set_bonus_threshold: pool.set_bonus_threshold,
+            paused: pool.is_paused(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl Contract {
+    /// Creates the reward pool of `nft_contract`, so that its tokens can be staked. The rate is in
+    /// the smallest unit of the reward token, see `get_reward_token_decimals`. If
+    /// `halving_interval_sec` is given, the rate halves every that many seconds from now on. Can
+    /// only be called by the owner.
+    pub fn create_pool(
+        &mut self,
+        nft_contract: AccountId,
+        reward_rate_per_second: U128,
+        halving_interval_sec: Option<u64>,
+    ) {
+        self.assert_owner();
+        assert!(self.pools.get(&nft_contract).is_none(), "Pool already exists");
+        self.pools.insert(
+            &nft_contract,
+            &Pool {
+                reward_rate_per_second: reward_rate_per_second.into(),
+                total_staked: 0,
+                enabled: true,
+                genesis_timestamp: env::block_timestamp(),
+                halving_interval_sec: halving_interval_sec.unwrap_or(0),
+                cliff_duration_sec: 0,
+                set_bonus_bps: 0,
+                set_bonus_threshold: 0,
+                paused_sec: 0,
+                paused_at: None,
+            },
+        );
+    }
+
+    /// Enables or disables new stakes in the pool of `nft_contract`. Existing stakes keep
+    /// earning. Can only be called by the owner.
+    pub fn set_pool_enabled(&mut self, nft_contract: AccountId, enabled: bool) {
+        self.assert_owner();
+        let mut pool = self.pools.get(&nft_contract).expect("Pool doesn't exist");
+        pool.enabled = enabled;
+        self.pools.insert(&nft_contract, &pool);
+    }
+
+    /// Sets the cliff of the pool of `nft_contract`: the seconds after staking during which a
+    /// stake earns nothing. Only applies to stakes made, or relocked, from now on. Can only be
+    /// called by the owner.
+    pub fn set_pool_cliff(&mut self, nft_contract: AccountId, cliff_duration_sec: u64) {
+        self.assert_owner();
+        let mut pool = self.internal_get_pool(&nft_contract);
+        pool.cliff_duration_sec = cliff_duration_sec;
+        self.pools.insert(&nft_contract, &pool);
+    }
+
+    /// Sets the set bonus of the pool of `nft_contract`: stakers with at least
+    /// `set_bonus_threshold` tokens of the collection staked earn `set_bonus_bps` more on all of
+    /// them, e.g. 5000 for 1.5x. The bonus is applied when a stake is settled, based on the
+    /// staker's count at that time, so it also covers the time since the stake's last
+    /// settlement. Can only be called by the owner.
+    pub fn set_pool_set_bonus(
+        &mut self,
+        nft_contract: AccountId,
+        set_bonus_bps: u32,
+        set_bonus_threshold: u32,
+    ) {
+        self.assert_owner();
+        assert!(
+            set_bonus_bps <= MAX_MULTIPLIER_BPS,
+            "Set bonus can't exceed {} bps",
+            MAX_MULTIPLIER_BPS
+        );
+        let mut pool = self.internal_get_pool(&nft_contract);
+        pool.set_bonus_bps = set_bonus_bps;
+        pool.set_bonus_threshold = set_bonus_threshold;
+        self.pools.insert(&nft_contract, &pool);
+    }
+
+    /// Pauses or resumes reward accrual in the pool of `nft_contract`. Rewards accrued before the
+    /// pause are kept, nothing accrues while paused, and accrual resumes from the time of the
+    /// unpause. Halvings and cliffs are delayed by the pause, as paused time doesn't count towards
+    /// them. Stakes and unstakes aren't affected. Can only be called by the owner.
+    pub fn set_pool_paused(&mut self, nft_contract: AccountId, paused: bool) {
+        self.assert_owner();
+        let mut pool = self.internal_get_pool(&nft_contract);
+        if pool.is_paused() == paused {
+            return;
+        }
+        let now_sec = env::block_timestamp() / NANOS_PER_SECOND;
+        if paused {
+            pool.paused_at = Some(now_sec);
+        } else {
+            pool.paused_sec = pool.paused_sec_at(now_sec);
+            pool.paused_at = None;
+        }
+        self.pools.insert(&nft_contract, &pool);
+    }
+
+    /// Returns the pool of `nft_contract`, if it exists.
+    pub fn get_pool(&self, nft_contract: AccountId) -> Option<PoolView> {
+        self.pools.get(&nft_contract).map(|pool| PoolView::new(nft_contract, pool))
+    }
+
+    /// Returns all pools.
+    pub fn get_all_pools(&self) -> Vec<PoolView> {
+        self.pools.iter().map(|(nft_contract, pool)| PoolView::new(nft_contract, pool)).collect()
+    }
+
+    /// Returns the number of tokens of `nft_contract` that are currently staked.
+    pub fn get_total_staked(&self, nft_contract: AccountId) -> u64 {
+        self.pools.get(&nft_contract).map_or(0, |pool| pool.total_staked)
+    }
+}
+
+impl Contract {
+    pub(crate) fn internal_get_pool(&self, nft_contract: &AccountId) -> Pool {
+        self.pools.get(nft_contract).expect("Pool doesn't exist")
+    }
+
+    /// Adds `staked` to the count of staked tokens of the pool of `nft_contract`, or removes
+    /// `unstaked` from it. Unstakes remove the token from the count before the NFT transfer, and
+    /// a failed transfer adds it back when the stake is restored.
+    pub(crate) fn internal_update_total_staked(
+        &mut self,
+        nft_contract: &AccountId,
+        staked: u64,
+        unstaked: u64,
+    ) {
+        let mut pool = self.internal_get_pool(nft_contract);
+        pool.total_staked = pool.total_staked + staked - unstaked;
+        self.pools.insert(nft_contract, &pool);
+    }
+}
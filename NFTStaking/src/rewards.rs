@@ -0,0 +1,56 @@
+use near_sdk::Balance;
+
+/// Integrates a reward rate over the seconds `from_sec..to_sec`. The rate starts at `base_rate`
+/// at `genesis_sec` and halves every `halving_interval_sec` seconds, so each halving period of
+/// the range is paid at its own rate. A zero interval means the rate never halves. Returns `None`
+/// on overflow.
+pub(crate) fn halving_rewards(
+    base_rate: Balance,
+    genesis_sec: u64,
+    halving_interval_sec: u64,
+    from_sec: u64,
+    to_sec: u64,
+) -> Option<Balance> {
+    if halving_interval_sec == 0 {
+        return Balance::from(to_sec.saturating_sub(from_sec)).checked_mul(base_rate);
+    }
+    let mut total: Balance = 0;
+    let mut start = from_sec;
+    while start < to_sec {
+        let halvings = start.saturating_sub(genesis_sec) / halving_interval_sec;
+        // Once the rate is shifted down to zero, nothing else accrues.
+        if halvings >= u64::from(Balance::BITS) || base_rate >> halvings == 0 {
+            break;
+        }
+        let period_end = (halvings + 1)
+            .checked_mul(halving_interval_sec)
+            .and_then(|offset| offset.checked_add(genesis_sec))
+            .unwrap_or(u64::MAX);
+        let end = period_end.min(to_sec);
+        let period_rewards = Balance::from(end - start).checked_mul(base_rate >> halvings)?;
+        total = total.checked_add(period_rewards)?;
+        start = end;
+    }
+    Some(total)
+}
+
+/// Rewards accrued by a stake, with the remainders of its divisions to carry into the next
+/// settlement.
+pub(crate) struct Accrual {
+    pub reward: Balance,
+    pub base_remainder: Balance,
+    pub bonus_remainder: Balance,
+    /// Seconds the pool had been paused for at the end of the accrual.
+    pub paused_sec: u64,
+}
+
+/// Divides `numerator` plus the `carry` of a previous division by `denominator`, returning the
+/// quotient and the new remainder to carry.
+pub(crate) fn divide_with_carry(
+    numerator: Balance,
+    carry: Balance,
+    denominator: Balance,
+) -> Option<(Balance, Balance)> {
+    let total = numerator.checked_add(carry)?;
+    Some((total / denominator, total % denominator))
+}
@@ -0,0 +1,183 @@
+use crate::*;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
+use near_sdk::{assert_one_yocto, StorageUsage};
+
+/// Storage deposit of an account and the bytes taken by its stakes.
+#[derive(BorshDeserialize, BorshSerialize)]
+pub struct StorageAccount {
+    pub deposit: Balance,
+    pub used_bytes: StorageUsage,
+}
+
+impl Contract {
+    /// Measures the storage taken by the registration of one account.
+    pub(crate) fn measure_account_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        self.storage_accounts
+            .insert(&tmp_account_id, &StorageAccount { deposit: 0, used_bytes: 0 });
+        self.account_storage_usage = env::storage_usage() - initial_storage_usage;
+        self.storage_accounts.remove(&tmp_account_id);
+    }
+
+    /// Charges the storage added since `initial_storage_usage` to the deposit of `account_id`,
+    /// or releases it back to the deposit if storage was freed. Panics if the account isn't
+    /// registered or the deposit doesn't cover the added storage.
+    pub(crate) fn internal_charge_storage(
+        &mut self,
+        account_id: &AccountId,
+        initial_storage_usage: StorageUsage,
+    ) {
+        let mut account = self
+            .storage_accounts
+            .get(account_id)
+            .expect("The account is not registered, call storage_deposit first");
+        account.used_bytes =
+            (account.used_bytes + env::storage_usage()).saturating_sub(initial_storage_usage);
+        let required = self.internal_locked_storage_balance(&account);
+        assert!(
+            account.deposit >= required,
+            "Insufficient storage deposit, the stake requires {} yoctoNEAR in total",
+            required
+        );
+        self.storage_accounts.insert(account_id, &account);
+    }
+
+    /// Like `internal_charge_storage`, but never panics, for state that has to be restored in a
+    /// callback. The deposit may end up not covering the storage.
+    pub(crate) fn internal_force_charge_storage(
+        &mut self,
+        account_id: &AccountId,
+        initial_storage_usage: StorageUsage,
+    ) {
+        if let Some(mut account) = self.storage_accounts.get(account_id) {
+            account.used_bytes =
+                (account.used_bytes + env::storage_usage()).saturating_sub(initial_storage_usage);
+            self.storage_accounts.insert(account_id, &account);
+        }
+    }
+
+    fn internal_locked_storage_balance(&self, account: &StorageAccount) -> Balance {
+        Balance::from(self.account_storage_usage + account.used_bytes) * env::storage_byte_cost()
+    }
+
+    fn internal_storage_balance(&self, account: &StorageAccount) -> StorageBalance {
+        StorageBalance {
+            total: account.deposit.into(),
+            available: account
+                .deposit
+                .saturating_sub(self.internal_locked_storage_balance(account))
+                .into(),
+        }
+    }
+}
+
+#[near_bindgen]
+impl StorageManagement for Contract {
+    /// Adds the attached deposit to the storage deposit of `account_id`, or of the caller. With
+    /// `registration_only`, only the minimum balance is kept and the rest is refunded.
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let amount = env::attached_deposit();
+        let account_id = account_id.unwrap_or_else(env::predecessor_account_id);
+        let min_balance = self.storage_balance_bounds().min.0;
+        let mut account = match self.storage_accounts.get(&account_id) {
+            Some(account) => account,
+            None => {
+                assert!(
+                    amount >= min_balance,
+                    "The attached deposit is less than the minimum storage balance"
+                );
+                StorageAccount { deposit: 0, used_bytes: 0 }
+            }
+        };
+        let deposit = if registration_only.unwrap_or(false) {
+            let deposit = if account.deposit == 0 { min_balance } else { 0 };
+            if amount > deposit {
+                Promise::new(env::predecessor_account_id()).transfer(amount - deposit);
+            }
+            deposit
+        } else {
+            amount
+        };
+        account.deposit += deposit;
+        self.storage_accounts.insert(&account_id, &account);
+        self.internal_storage_balance(&account)
+    }
+
+    /// Withdraws `amount`, or all of the available storage balance, to the caller. Requires
+    /// exactly 1 yoctoNEAR attached.
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        let mut account =
+            self.storage_accounts.get(&account_id).expect("The account is not registered");
+        let available = self.internal_storage_balance(&account).available.0;
+        let amount = amount.map_or(available, |amount| amount.0);
+        assert!(amount <= available, "The amount is greater than the available storage balance");
+        account.deposit -= amount;
+        self.storage_accounts.insert(&account_id, &account);
+        if amount > 0 {
+            Promise::new(account_id).transfer(amount);
+        }
+        self.internal_storage_balance(&account)
+    }
+
+    /// Unregisters the caller and refunds the whole deposit, forgetting its lifetime claims. Panics
+    /// while the caller has stakes, even with `force`, since the staked NFTs must be unstaked
+    /// first. Unclaimed rewards have to be claimed first too, unless `force` is set, which forfeits
+    /// them to the reserve, e.g. for a balance below the minimum claim amount. Returns `false` if
+    /// the caller isn't registered. Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        match self.storage_accounts.get(&account_id) {
+            Some(account) => {
+                assert!(
+                    self.staker_tokens.get(&account_id).is_none(),
+                    "Can't unregister the account with active stakes"
+                );
+                if force.unwrap_or(false) {
+                    // The reserve still holds the forfeited rewards, they are just no longer owed.
+                    if let Some(unclaimed) = self.unclaimed_rewards.remove(&account_id) {
+                        log!("Forfeited {} unclaimed rewards of @{}", unclaimed, account_id);
+                    }
+                } else {
+                    assert!(
+                        self.unclaimed_rewards.get(&account_id).is_none(),
+                        "Can't unregister the account with unclaimed rewards"
+                    );
+                }
+                self.storage_accounts.remove(&account_id);
+                self.account_lifetime_claimed.remove(&account_id);
+                Promise::new(account_id).transfer(account.deposit + 1);
+                true
+            }
+            None => {
+                log!("The account {} is not registered", account_id);
+                false
+            }
+        }
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        StorageBalanceBounds {
+            min: (Balance::from(self.account_storage_usage) * env::storage_byte_cost()).into(),
+            max: None,
+        }
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        self.storage_accounts
+            .get(&account_id)
+            .map(|account| self.internal_storage_balance(&account))
+    }
+}
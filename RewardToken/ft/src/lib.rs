@@ -15,18 +15,95 @@ NOTES:
   - To prevent the deployed contract from being modified or deleted, it should not have any access
     keys on its account.
 */
+use near_contract_standards::fungible_token::core::FungibleTokenCore;
 use near_contract_standards::fungible_token::metadata::{
     FungibleTokenMetadata, FungibleTokenMetadataProvider, FT_METADATA_SPEC,
 };
+use near_contract_standards::fungible_token::resolver::FungibleTokenResolver;
 use near_contract_standards::fungible_token::FungibleToken;
+use near_contract_standards::storage_management::{
+    StorageBalance, StorageBalanceBounds, StorageManagement,
+};
 use near_sdk::borsh::{self, BorshDeserialize, BorshSerialize};
-use near_sdk::collections::LazyOption;
+use near_sdk::collections::{LazyOption, LookupMap, LookupSet, UnorderedSet};
 use near_sdk::json_types::U128;
-use near_sdk::{env, log, near_bindgen, AccountId, Balance, PanicOnDefault, PromiseOrValue};
+use near_sdk::{
+    assert_one_yocto, env, log, near_bindgen, AccountId, Balance, PanicOnDefault, Promise,
+    PromiseOrValue, StorageUsage,
+};
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+/// Largest icon the metadata can hold, in bytes, so that the metadata stays cheap to store and
+/// to fetch. Fits the default icon.
+const MAX_ICON_BYTES: usize = 8 * 1024;
+
+/// Tokens granted by `mint_vested` that unlock linearly over `duration_sec` after `start_sec`,
+/// with nothing unlocked before the cliff.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct VestingSchedule {
+    total: Balance,
+    claimed: Balance,
+    start_sec: u64,
+    cliff_sec: u64,
+    duration_sec: u64,
+}
+
+impl VestingSchedule {
+    /// Returns the amount unlocked at `now_sec`, including what was already claimed.
+    fn vested_at(&self, now_sec: u64) -> Balance {
+        let elapsed = now_sec.saturating_sub(self.start_sec);
+        if elapsed < self.cliff_sec {
+            0
+        } else if elapsed >= self.duration_sec {
+            // Released in full rather than through the division below, so no dust is left.
+            self.total
+        } else {
+            self.total.checked_mul(elapsed as Balance).expect("Vesting amount overflow")
+                / self.duration_sec as Balance
+        }
+    }
+}
 
 #[near_bindgen]
 #[derive(BorshDeserialize, BorshSerialize, PanicOnDefault)]
 pub struct Contract {
+    owner_id: AccountId,
+    /// Account proposed by the owner that still has to call `accept_ownership`.
+    pending_owner: Option<AccountId>,
+    token: FungibleToken,
+    metadata: LazyOption<FungibleTokenMetadata>,
+    /// Registered accounts, kept alongside `token.accounts` (which can't be iterated) so that
+    /// holders can be enumerated by `ft_accounts`.
+    holders: UnorderedSet<AccountId>,
+    /// Per-holder spender allowed to pull tokens via `transfer_from`, with the remaining amount
+    /// it may still pull. `u128::MAX` means the spender is uncapped.
+    trusted_spenders: LookupMap<AccountId, (AccountId, Balance)>,
+    /// Total amount ever minted, including the initial supply. Burns don't reduce it.
+    total_minted: Balance,
+    /// Hard cap on `ft_total_supply` enforced by `mint` and `mint_vested`. `None` means
+    /// unlimited.
+    max_supply: Option<Balance>,
+    /// While set, `ft_transfer`, `ft_transfer_call` and `transfer_from` are rejected. Minting
+    /// and burning are still allowed so the owner can manage supply.
+    paused: bool,
+    /// Accounts besides the owner that can call `mint`, e.g. the staking contract.
+    minters: UnorderedSet<AccountId>,
+    /// Accounts that can neither send, receive nor burn tokens. The owner can still mint to
+    /// them and burn their tokens with `burn_from_frozen`.
+    frozen: UnorderedSet<AccountId>,
+    /// Vesting grants per account. Their tokens are minted, and count towards
+    /// `ft_total_supply`, when granted, but stay locked here until they are claimed.
+    vesting: LookupMap<AccountId, VestingSchedule>,
+    /// Per-account storage usage before `migrate` raised it, which is what the accounts
+    /// registered until then paid for. `None` if the contract wasn't migrated.
+    legacy_account_storage_usage: Option<StorageUsage>,
+    /// Accounts registered before `migrate` that `migrate_holders` added to `holders`.
+    legacy_holders: LookupSet<AccountId>,
+}
+
+/// State layout of the first deployed version of the contract, read by `migrate`.
+#[derive(BorshDeserialize, BorshSerialize)]
+struct OldContract {
     token: FungibleToken,
     metadata: LazyOption<FungibleTokenMetadata>,
 }
@@ -53,24 +130,43 @@ impl Contract {
                 reference_hash: None,
                 decimals: 18,
             },
+            None,
         )
     }
 
     /// Initializes the contract with the given total supply owned by the given `owner_id` with
-    /// the given fungible token metadata.
+    /// the given fungible token metadata. If `max_supply` is given, `mint` can never raise the
+    /// total supply above it.
     #[init]
     pub fn new(
         owner_id: AccountId,
         total_supply: U128,
         metadata: FungibleTokenMetadata,
+        max_supply: Option<U128>,
     ) -> Self {
         assert!(!env::state_exists(), "Already initialized");
         metadata.assert_valid();
+        if let Some(max_supply) = max_supply {
+            assert!(total_supply.0 <= max_supply.0, "Total supply exceeds max supply");
+        }
         let mut this = Self {
+            owner_id: owner_id.clone(),
+            pending_owner: None,
             token: FungibleToken::new(b"a".to_vec()),
             metadata: LazyOption::new(b"m".to_vec(), Some(&metadata)),
+            holders: UnorderedSet::new(b"h".to_vec()),
+            trusted_spenders: LookupMap::new(b"t".to_vec()),
+            minters: UnorderedSet::new(b"n".to_vec()),
+            frozen: UnorderedSet::new(b"f".to_vec()),
+            vesting: LookupMap::new(b"v".to_vec()),
+            legacy_account_storage_usage: None,
+            legacy_holders: LookupSet::new(b"l".to_vec()),
+            total_minted: total_supply.into(),
+            max_supply: max_supply.map(|max_supply| max_supply.0),
+            paused: false,
         };
-        this.token.internal_register_account(&owner_id);
+        this.measure_holder_storage_usage();
+        this.internal_register_account(&owner_id);
         this.token.internal_deposit(&owner_id, total_supply.into());
         near_contract_standards::fungible_token::events::FtMint {
             owner_id: &owner_id,
@@ -81,6 +177,521 @@ impl Contract {
         this
     }
 
+    /// Upgrades the state of a contract deployed with the first version, which only had the token
+    /// and its metadata. The contract account becomes the owner, `total_minted` starts at the
+    /// current total supply, and the contract is unpaused with no max supply. Accounts registered
+    /// before the migration keep the storage deposit they paid, and aren't listed by
+    /// `ft_accounts` until they are passed to `migrate_holders`. Panics if the state isn't in the
+    /// old layout, so it can't be run twice.
+    #[private]
+    #[init(ignore_state)]
+    pub fn migrate() -> Self {
+        let old: OldContract = env::state_read().expect("Old state doesn't exist");
+        let legacy_account_storage_usage = old.token.account_storage_usage;
+        let mut this = Self {
+            owner_id: env::current_account_id(),
+            pending_owner: None,
+            total_minted: old.token.total_supply,
+            token: old.token,
+            metadata: old.metadata,
+            holders: UnorderedSet::new(b"h".to_vec()),
+            trusted_spenders: LookupMap::new(b"t".to_vec()),
+            minters: UnorderedSet::new(b"n".to_vec()),
+            frozen: UnorderedSet::new(b"f".to_vec()),
+            vesting: LookupMap::new(b"v".to_vec()),
+            legacy_account_storage_usage: Some(legacy_account_storage_usage),
+            legacy_holders: LookupSet::new(b"l".to_vec()),
+            max_supply: None,
+            paused: false,
+        };
+        this.measure_holder_storage_usage();
+        this
+    }
+
+    /// Adds the accounts in `account_ids` that were registered before `migrate` to the holders
+    /// listed by `ft_accounts`, skipping accounts that aren't registered or are already listed.
+    /// The accounts can be passed in batches, so that each call stays within the gas limit. The
+    /// storage of the new entries is paid by the contract. Can only be called by the owner.
+    pub fn migrate_holders(&mut self, account_ids: Vec<AccountId>) {
+        self.assert_owner();
+        assert!(self.legacy_account_storage_usage.is_some(), "The contract wasn't migrated");
+        for account_id in account_ids {
+            if self.token.accounts.contains_key(&account_id) && !self.holders.contains(&account_id)
+            {
+                self.holders.insert(&account_id);
+                self.legacy_holders.insert(&account_id);
+            }
+        }
+    }
+
+    /// Lets `account_id` (typically the staking contract) pull the caller's tokens through
+    /// `transfer_from` without a separate approval each time. When `cap` is given, it bounds the
+    /// total amount the spender can pull; otherwise the spender is uncapped. Replaces any
+    /// previously trusted spender. Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn set_trusted_spender(&mut self, account_id: AccountId, cap: Option<U128>) {
+        assert_one_yocto();
+        let owner_id = env::predecessor_account_id();
+        // Only registered holders can set a spender, which bounds the map to one entry per account.
+        self.token.internal_unwrap_balance_of(&owner_id);
+        let cap = cap.map(|cap| cap.0).unwrap_or(u128::MAX);
+        self.trusted_spenders.insert(&owner_id, &(account_id, cap));
+    }
+
+    /// Revokes the caller's trusted spender, if any. Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn remove_trusted_spender(&mut self) {
+        assert_one_yocto();
+        self.trusted_spenders.remove(&env::predecessor_account_id());
+    }
+
+    /// Returns the trusted spender of `account_id` and the amount it can still pull.
+    pub fn trusted_spender(&self, account_id: AccountId) -> Option<(AccountId, U128)> {
+        self.trusted_spenders.get(&account_id).map(|(spender_id, cap)| (spender_id, cap.into()))
+    }
+
+    /// Moves `amount` tokens from `owner_id` to `receiver_id`. Can only be called by the trusted
+    /// spender of `owner_id`, and only up to its remaining cap. Requires exactly 1 yoctoNEAR
+    /// attached.
+    #[payable]
+    pub fn transfer_from(
+        &mut self,
+        owner_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+    ) {
+        assert_one_yocto();
+        self.assert_not_paused();
+        let (spender_id, cap) =
+            self.trusted_spenders.get(&owner_id).expect("The account has no trusted spender");
+        assert_eq!(
+            env::predecessor_account_id(),
+            spender_id,
+            "Only the trusted spender can transfer from this account"
+        );
+        self.assert_not_frozen(&owner_id);
+        self.assert_not_frozen(&receiver_id);
+        let amount: Balance = amount.into();
+        assert!(amount <= cap, "The amount exceeds the trusted spender cap");
+        self.token.internal_transfer(&owner_id, &receiver_id, amount, memo);
+        if cap != u128::MAX {
+            self.trusted_spenders.insert(&owner_id, &(spender_id, cap - amount));
+        }
+    }
+
+    /// Mints `amount` new tokens to `account_id`, registering the account first if needed.
+    /// Can only be called by the owner or a minter.
+    pub fn mint(&mut self, account_id: AccountId, amount: U128, memo: Option<String>) {
+        self.assert_minter();
+        self.assert_within_max_supply(amount.0);
+        if !self.token.accounts.contains_key(&account_id) {
+            self.internal_register_account(&account_id);
+        }
+        self.token.internal_deposit(&account_id, amount.into());
+        self.total_minted += amount.0;
+        near_contract_standards::fungible_token::events::FtMint {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: memo.as_deref(),
+        }
+        .emit();
+    }
+
+    /// Mints tokens to every `(account_id, amount)` pair in `mints`, registering accounts as
+    /// needed, and emits a single `ft_mint` event covering all of them. The whole batch is checked
+    /// against the supply cap before any balance changes. Can only be called by the owner or a
+    /// minter.
+    pub fn mint_many(&mut self, mints: Vec<(AccountId, U128)>) {
+        self.assert_minter();
+        let total = mints
+            .iter()
+            .try_fold(0, |total: Balance, (_, amount)| total.checked_add(amount.0))
+            .expect("Total amount overflow");
+        self.assert_within_max_supply(total);
+
+        for (account_id, amount) in mints.iter() {
+            if !self.token.accounts.contains_key(account_id) {
+                self.internal_register_account(account_id);
+            }
+            self.token.internal_deposit(account_id, amount.0);
+        }
+        self.total_minted += total;
+        let events: Vec<_> = mints
+            .iter()
+            .map(|(account_id, amount)| near_contract_standards::fungible_token::events::FtMint {
+                owner_id: account_id,
+                amount,
+                memo: None,
+            })
+            .collect();
+        near_contract_standards::fungible_token::events::FtMint::emit_many(&events);
+    }
+
+    /// Grants `total` tokens to `account_id` that unlock linearly over `duration_sec`, starting
+    /// now. Nothing unlocks before `cliff_sec` has passed. The grant is minted right away, with an
+    /// `ft_mint` event, but stays locked outside the account balance until the unlocked part is
+    /// moved there with `claim_vested`. An account can only have one schedule at a time. Can
+    /// only be called by the owner or a minter.
+    pub fn mint_vested(
+        &mut self,
+        account_id: AccountId,
+        total: U128,
+        cliff_sec: u64,
+        duration_sec: u64,
+    ) {
+        self.assert_minter();
+        assert!(total.0 > 0, "The amount should be a positive number");
+        assert!(duration_sec > 0, "Vesting duration should be positive");
+        assert!(cliff_sec <= duration_sec, "Cliff can't be longer than the vesting duration");
+        assert!(
+            self.vesting.get(&account_id).is_none(),
+            "The account already has a vesting schedule"
+        );
+        self.assert_within_max_supply(total.0);
+        if !self.token.accounts.contains_key(&account_id) {
+            self.internal_register_account(&account_id);
+        }
+        self.vesting.insert(
+            &account_id,
+            &VestingSchedule {
+                total: total.0,
+                claimed: 0,
+                start_sec: env::block_timestamp() / NANOS_PER_SECOND,
+                cliff_sec,
+                duration_sec,
+            },
+        );
+        self.token.total_supply += total.0;
+        self.total_minted += total.0;
+        near_contract_standards::fungible_token::events::FtMint {
+            owner_id: &account_id,
+            amount: &total,
+            memo: Some("Vesting grant"),
+        }
+        .emit();
+    }
+
+    /// Moves the caller's unlocked vesting tokens to their balance and returns the amount. They
+    /// were minted with the grant, so the total supply doesn't change.
+    pub fn claim_vested(&mut self) -> U128 {
+        let account_id = env::predecessor_account_id();
+        let mut schedule =
+            self.vesting.get(&account_id).expect("The account has no vesting schedule");
+        let amount =
+            schedule.vested_at(env::block_timestamp() / NANOS_PER_SECOND) - schedule.claimed;
+        assert!(amount > 0, "No vested tokens to claim");
+        schedule.claimed += amount;
+        if schedule.claimed == schedule.total {
+            self.vesting.remove(&account_id);
+        } else {
+            self.vesting.insert(&account_id, &schedule);
+        }
+        let balance = self.token.internal_unwrap_balance_of(&account_id);
+        self.token
+            .accounts
+            .insert(&account_id, &balance.checked_add(amount).expect("Balance overflow"));
+        log!("Released {} vested tokens to @{}", amount, account_id);
+        amount.into()
+    }
+
+    /// Returns the unlocked vesting tokens `account_id` can claim now.
+    pub fn vested_available(&self, account_id: AccountId) -> U128 {
+        self.vesting
+            .get(&account_id)
+            .map(|schedule| {
+                schedule.vested_at(env::block_timestamp() / NANOS_PER_SECOND) - schedule.claimed
+            })
+            .unwrap_or(0)
+            .into()
+    }
+
+    /// Burns `amount` of the caller's tokens, reducing the total supply. The storage deposit
+    /// stays with the account even if its balance drops to zero, and is only refunded by
+    /// `storage_unregister`. Frozen accounts can't burn. Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn burn(&mut self, amount: U128) {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        self.assert_not_frozen(&account_id);
+        self.internal_burn(account_id, amount);
+    }
+
+    /// Burns `amount` of the tokens held by the frozen `account_id`, e.g. to take stolen funds
+    /// out of circulation. Can only be called by the owner, with exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn burn_from_frozen(&mut self, account_id: AccountId, amount: U128) {
+        assert_one_yocto();
+        self.assert_owner();
+        assert!(self.frozen.contains(&account_id), "Account is not frozen");
+        self.internal_burn(account_id, amount);
+    }
+
+    /// Proposes `new_owner` as the next owner. Ownership only moves once `new_owner` calls
+    /// `accept_ownership`, so a mistyped account can't lock the owner out. Can only be called by
+    /// the owner.
+    pub fn propose_owner(&mut self, new_owner: AccountId) {
+        self.assert_owner();
+        self.pending_owner = Some(new_owner);
+    }
+
+    /// Completes an ownership transfer. Can only be called by the pending owner.
+    pub fn accept_ownership(&mut self) {
+        let account_id = env::predecessor_account_id();
+        assert_eq!(
+            self.pending_owner.as_ref(),
+            Some(&account_id),
+            "Only the pending owner can accept ownership"
+        );
+        self.owner_id = account_id;
+        self.pending_owner = None;
+    }
+
+    /// Returns the current owner.
+    pub fn get_owner(&self) -> AccountId {
+        self.owner_id.clone()
+    }
+
+    /// Returns the account that was proposed as the next owner, if any.
+    pub fn get_pending_owner(&self) -> Option<AccountId> {
+        self.pending_owner.clone()
+    }
+
+    /// Allows `account_id` to mint tokens. Can only be called by the owner.
+    pub fn add_minter(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.minters.insert(&account_id);
+    }
+
+    /// Revokes the minting rights of `account_id`. Can only be called by the owner.
+    pub fn remove_minter(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.minters.remove(&account_id);
+    }
+
+    /// Returns whether `account_id` was granted minting rights. The owner can always mint.
+    pub fn is_minter(&self, account_id: AccountId) -> bool {
+        self.minters.contains(&account_id)
+    }
+
+    pub fn get_minters(&self) -> Vec<AccountId> {
+        self.minters.to_vec()
+    }
+
+    /// Transfers tokens from the caller to every `(receiver_id, amount)` pair in `transfers` and
+    /// emits a single `ft_transfer` event covering all of them. The whole batch is validated
+    /// before any balance changes, so it either succeeds entirely or panics without side effects.
+    /// Requires exactly 1 yoctoNEAR attached.
+    #[payable]
+    pub fn ft_transfer_many(&mut self, transfers: Vec<(AccountId, U128)>, memo: Option<String>) {
+        assert_one_yocto();
+        assert!(!transfers.is_empty(), "Transfers can't be empty");
+        self.assert_not_paused();
+        let sender_id = env::predecessor_account_id();
+        self.assert_not_frozen(&sender_id);
+        let mut total: Balance = 0;
+        for (receiver_id, amount) in transfers.iter() {
+            assert_ne!(&sender_id, receiver_id, "Sender and receiver should be different");
+            self.assert_not_frozen(receiver_id);
+            assert!(amount.0 > 0, "The amount should be a positive number");
+            self.token.internal_unwrap_balance_of(receiver_id);
+            total = total.checked_add(amount.0).expect("Total amount overflow");
+        }
+        assert!(
+            self.token.internal_unwrap_balance_of(&sender_id) >= total,
+            "The account doesn't have enough balance"
+        );
+
+        for (receiver_id, amount) in transfers.iter() {
+            self.token.internal_withdraw(&sender_id, amount.0);
+            self.token.internal_deposit(receiver_id, amount.0);
+        }
+        let events: Vec<_> = transfers
+            .iter()
+            .map(|(receiver_id, amount)| {
+                near_contract_standards::fungible_token::events::FtTransfer {
+                    old_owner_id: &sender_id,
+                    new_owner_id: receiver_id,
+                    amount,
+                    memo: memo.as_deref(),
+                }
+            })
+            .collect();
+        near_contract_standards::fungible_token::events::FtTransfer::emit_many(&events);
+    }
+
+    /// Replaces the token metadata. `decimals` can't be changed after initialization, because
+    /// that would silently change every displayed balance. Can only be called by the owner.
+    pub fn update_metadata(&mut self, metadata: FungibleTokenMetadata) {
+        self.assert_owner();
+        metadata.assert_valid();
+        Self::assert_valid_icon(&metadata.icon);
+        assert_eq!(
+            metadata.decimals,
+            self.metadata.get().unwrap().decimals,
+            "Decimals can't be changed"
+        );
+        self.metadata.set(&metadata);
+    }
+
+    /// Replaces only the icon of the token metadata, or removes it if `icon` is `None`. Panics if
+    /// the icon is larger than `MAX_ICON_BYTES`. Can only be called by the owner.
+    pub fn set_icon(&mut self, icon: Option<String>) {
+        self.assert_owner();
+        Self::assert_valid_icon(&icon);
+        let mut metadata = self.metadata.get().unwrap();
+        metadata.icon = icon;
+        metadata.assert_valid();
+        self.metadata.set(&metadata);
+    }
+
+    /// Pauses or resumes token transfers. Can only be called by the owner.
+    pub fn set_paused(&mut self, paused: bool) {
+        self.assert_owner();
+        self.paused = paused;
+    }
+
+    /// Returns whether token transfers are paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Blocks all transfers from and to `account_id`. Can only be called by the owner.
+    pub fn freeze_account(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.frozen.insert(&account_id);
+    }
+
+    /// Lifts a freeze set by `freeze_account`. Can only be called by the owner.
+    pub fn unfreeze_account(&mut self, account_id: AccountId) {
+        self.assert_owner();
+        self.frozen.remove(&account_id);
+    }
+
+    /// Returns whether transfers from and to `account_id` are blocked.
+    pub fn is_frozen(&self, account_id: AccountId) -> bool {
+        self.frozen.contains(&account_id)
+    }
+
+    /// Returns the supply cap enforced by `mint`, if any.
+    pub fn ft_max_supply(&self) -> Option<U128> {
+        self.max_supply.map(U128)
+    }
+
+    /// Returns registered accounts with their balances, in registration order. Defaults to the
+    /// first 50 accounts.
+    pub fn ft_accounts(
+        &self,
+        from_index: Option<u64>,
+        limit: Option<u64>,
+    ) -> Vec<(AccountId, U128)> {
+        self.holders
+            .as_vector()
+            .iter()
+            .skip(from_index.unwrap_or(0) as usize)
+            .take(limit.unwrap_or(50) as usize)
+            .map(|account_id| {
+                let balance = self.token.accounts.get(&account_id).unwrap_or(0);
+                (account_id, balance.into())
+            })
+            .collect()
+    }
+
+    /// Returns the total amount of tokens ever minted. Unlike `ft_total_supply`, this figure
+    /// never decreases.
+    pub fn total_minted(&self) -> U128 {
+        self.total_minted.into()
+    }
+
+    /// Returns the storage deposit a caller has to attach to register `receiver_id` before
+    /// transferring to it, or zero if the receiver is already registered.
+    pub fn transfer_registration_cost(&self, receiver_id: AccountId) -> U128 {
+        if self.token.accounts.contains_key(&receiver_id) {
+            U128(0)
+        } else {
+            self.storage_balance_bounds().min
+        }
+    }
+
+    fn internal_burn(&mut self, account_id: AccountId, amount: U128) {
+        self.token.internal_withdraw(&account_id, amount.into());
+        near_contract_standards::fungible_token::events::FtBurn {
+            owner_id: &account_id,
+            amount: &amount,
+            memo: None,
+        }
+        .emit();
+        self.on_tokens_burned(account_id, amount.into());
+    }
+
+    /// Returns the storage deposit `account_id` paid on registration: the minimum from before
+    /// `migrate` for accounts registered until then, the current minimum for the others.
+    fn internal_storage_deposit_of(&self, account_id: &AccountId) -> Balance {
+        match self.legacy_account_storage_usage {
+            Some(usage)
+                if !self.holders.contains(account_id)
+                    || self.legacy_holders.contains(account_id) =>
+            {
+                Balance::from(usage) * env::storage_byte_cost()
+            }
+            _ => self.storage_balance_bounds().min.0,
+        }
+    }
+
+    fn internal_register_account(&mut self, account_id: &AccountId) {
+        self.token.internal_register_account(account_id);
+        self.holders.insert(account_id);
+    }
+
+    /// Adds the storage of a `holders` entry to the per-account storage usage, so that the
+    /// storage deposit required on registration also covers it.
+    fn measure_holder_storage_usage(&mut self) {
+        let initial_storage_usage = env::storage_usage();
+        let tmp_account_id = AccountId::new_unchecked("a".repeat(64));
+        self.holders.insert(&tmp_account_id);
+        self.token.account_storage_usage += env::storage_usage() - initial_storage_usage;
+        self.holders.remove(&tmp_account_id);
+    }
+
+    /// Panics if minting `amount` on top of the supply would exceed `max_supply`.
+    fn assert_within_max_supply(&self, amount: Balance) {
+        let new_supply =
+            self.token.total_supply.checked_add(amount).expect("Total supply overflow");
+        if let Some(max_supply) = self.max_supply {
+            assert!(new_supply <= max_supply, "Minting would exceed max supply");
+        }
+    }
+
+    fn assert_minter(&self) {
+        let account_id = env::predecessor_account_id();
+        assert!(
+            account_id == self.owner_id || self.minters.contains(&account_id),
+            "Only the owner or a minter can mint"
+        );
+    }
+
+    fn assert_owner(&self) {
+        assert_eq!(
+            env::predecessor_account_id(),
+            self.owner_id,
+            "Only the owner can call this method"
+        );
+    }
+
+    fn assert_not_paused(&self) {
+        assert!(!self.paused, "Transfers are paused");
+    }
+
+    fn assert_not_frozen(&self, account_id: &AccountId) {
+        assert!(!self.frozen.contains(account_id), "Account is frozen");
+    }
+
+    fn assert_valid_icon(icon: &Option<String>) {
+        if let Some(icon) = icon {
+            assert!(icon.len() <= MAX_ICON_BYTES, "Icon can't exceed {} bytes", MAX_ICON_BYTES);
+        }
+    }
+
     fn on_account_closed(&mut self, account_id: AccountId, balance: Balance) {
         log!("Closed @{} with {}", account_id, balance);
     }
@@ -90,8 +701,133 @@ impl Contract {
     }
 }
 
-near_contract_standards::impl_fungible_token_core!(Contract, token, on_tokens_burned);
-near_contract_standards::impl_fungible_token_storage!(Contract, token, on_account_closed);
+// The core methods are implemented by hand rather than with `impl_fungible_token_core!` so that
+// transfers can be paused and frozen accounts can be blocked.
+#[near_bindgen]
+impl FungibleTokenCore for Contract {
+    #[payable]
+    fn ft_transfer(&mut self, receiver_id: AccountId, amount: U128, memo: Option<String>) {
+        self.assert_not_paused();
+        self.assert_not_frozen(&env::predecessor_account_id());
+        self.assert_not_frozen(&receiver_id);
+        self.token.ft_transfer(receiver_id, amount, memo)
+    }
+
+    #[payable]
+    fn ft_transfer_call(
+        &mut self,
+        receiver_id: AccountId,
+        amount: U128,
+        memo: Option<String>,
+        msg: String,
+    ) -> PromiseOrValue<U128> {
+        self.assert_not_paused();
+        self.assert_not_frozen(&env::predecessor_account_id());
+        self.assert_not_frozen(&receiver_id);
+        self.token.ft_transfer_call(receiver_id, amount, memo, msg)
+    }
+
+    fn ft_total_supply(&self) -> U128 {
+        self.token.ft_total_supply()
+    }
+
+    fn ft_balance_of(&self, account_id: AccountId) -> U128 {
+        self.token.ft_balance_of(account_id)
+    }
+}
+
+#[near_bindgen]
+impl FungibleTokenResolver for Contract {
+    /// Refunds the amount the receiver didn't use, as far as the receiver still holds it, and
+    /// returns the amount that was used. If the receiver closed its account in the meantime,
+    /// nothing is refunded: closing an account burns its balance, so the tokens already left the
+    /// supply. If the sender closed its account, the refund has nowhere to go and is burned with
+    /// an `ft_burn` event, so `ft_total_supply` always matches the sum of the balances and of the
+    /// locked vesting tokens.
+    #[private]
+    fn ft_resolve_transfer(
+        &mut self,
+        sender_id: AccountId,
+        receiver_id: AccountId,
+        amount: U128,
+    ) -> U128 {
+        let (used_amount, burned_amount) =
+            self.token.internal_ft_resolve_transfer(&sender_id, receiver_id, amount);
+        if burned_amount > 0 {
+            self.on_tokens_burned(sender_id, burned_amount);
+        }
+        used_amount.into()
+    }
+}
+
+// Storage management is implemented by hand rather than with `impl_fungible_token_storage!` to
+// keep `holders` in sync with registrations.
+#[near_bindgen]
+impl StorageManagement for Contract {
+    #[payable]
+    fn storage_deposit(
+        &mut self,
+        account_id: Option<AccountId>,
+        registration_only: Option<bool>,
+    ) -> StorageBalance {
+        let registered_id = account_id.clone().unwrap_or_else(env::predecessor_account_id);
+        let storage_balance = self.token.storage_deposit(account_id, registration_only);
+        self.holders.insert(&registered_id);
+        storage_balance
+    }
+
+    #[payable]
+    fn storage_withdraw(&mut self, amount: Option<U128>) -> StorageBalance {
+        self.token.storage_withdraw(amount)
+    }
+
+    /// Refuses to close an account with a vesting schedule, whose unclaimed tokens would
+    /// otherwise be left without an owner. Refunds the storage deposit the account paid, which
+    /// is smaller for accounts registered before `migrate`.
+    #[payable]
+    fn storage_unregister(&mut self, force: Option<bool>) -> bool {
+        assert_one_yocto();
+        let account_id = env::predecessor_account_id();
+        assert!(
+            !self.vesting.contains_key(&account_id),
+            "Can't unregister an account with a vesting schedule"
+        );
+        let balance = match self.token.accounts.get(&account_id) {
+            Some(balance) => balance,
+            None => {
+                log!("The account {} is not registered", account_id);
+                return false;
+            }
+        };
+        assert!(
+            balance == 0 || force.unwrap_or(false),
+            "Can't unregister the account with the positive balance without force"
+        );
+        let refund = self.internal_storage_deposit_of(&account_id);
+        self.token.accounts.remove(&account_id);
+        self.token.total_supply -= balance;
+        self.holders.remove(&account_id);
+        self.legacy_holders.remove(&account_id);
+        Promise::new(account_id.clone()).transfer(refund + 1);
+        self.on_account_closed(account_id, balance);
+        true
+    }
+
+    fn storage_balance_bounds(&self) -> StorageBalanceBounds {
+        self.token.storage_balance_bounds()
+    }
+
+    fn storage_balance_of(&self, account_id: AccountId) -> Option<StorageBalance> {
+        if self.token.accounts.contains_key(&account_id) {
+            Some(StorageBalance {
+                total: self.internal_storage_deposit_of(&account_id).into(),
+                available: 0.into(),
+            })
+        } else {
+            None
+        }
+    }
+}
 
 #[near_bindgen]
 impl FungibleTokenMetadataProvider for Contract {
@@ -102,9 +838,9 @@ impl FungibleTokenMetadataProvider for Contract {
 
 #[cfg(all(test, not(target_arch = "wasm32")))]
 mod tests {
-    use near_sdk::test_utils::{accounts, VMContextBuilder};
+    use near_sdk::test_utils::{self, accounts, VMContextBuilder};
     use near_sdk::MockedBlockchain;
-    use near_sdk::{testing_env, Balance};
+    use near_sdk::{testing_env, Balance, PromiseResult};
 
     use super::*;
 
@@ -129,6 +865,54 @@ mod tests {
         assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
     }
 
+    #[test]
+    fn test_migrate() {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut old = OldContract {
+            token: FungibleToken::new(b"a".to_vec()),
+            metadata: LazyOption::new(b"m".to_vec(), None),
+        };
+        old.token.internal_register_account(&accounts(1));
+        old.token.internal_deposit(&accounts(1), TOTAL_SUPPLY);
+        let old_account_storage_usage = old.token.account_storage_usage;
+        env::state_write(&old);
+
+        let mut contract = Contract::migrate();
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.get_owner(), accounts(0));
+        assert_eq!(contract.get_pending_owner(), None);
+        assert!(!contract.is_paused());
+        assert_eq!(contract.ft_max_supply(), None);
+        assert_eq!(contract.total_minted().0, TOTAL_SUPPLY);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, TOTAL_SUPPLY);
+        assert!(contract.token.account_storage_usage > old_account_storage_usage);
+        // The account registered before the migration keeps its old deposit.
+        let old_deposit = Balance::from(old_account_storage_usage) * env::storage_byte_cost();
+        assert_eq!(contract.storage_balance_of(accounts(1)).unwrap().total.0, old_deposit);
+        assert!(contract.ft_accounts(None, None).is_empty());
+
+        testing_env!(context.is_view(false).build());
+        contract.migrate_holders(vec![accounts(1), accounts(2)]);
+        assert_eq!(contract.ft_accounts(None, None), vec![(accounts(1), TOTAL_SUPPLY.into())]);
+        assert_eq!(contract.storage_balance_of(accounts(1)).unwrap().total.0, old_deposit);
+
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        assert!(contract.storage_unregister(Some(true)));
+        assert!(contract.ft_accounts(None, None).is_empty());
+        assert_eq!(contract.ft_total_supply().0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "The contract wasn't migrated")]
+    fn test_migrate_holders_not_migrated() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        contract.migrate_holders(vec![accounts(1)]);
+    }
+
     #[test]
     #[should_panic(expected = "The contract is not initialized")]
     fn test_default() {
@@ -167,4 +951,745 @@ mod tests {
         assert_eq!(contract.ft_balance_of(accounts(2)).0, (TOTAL_SUPPLY - transfer_amount));
         assert_eq!(contract.ft_balance_of(accounts(1)).0, transfer_amount);
     }
+
+    #[test]
+    fn test_total_minted() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        assert_eq!(contract.total_minted().0, TOTAL_SUPPLY);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.ft_transfer(accounts(1), (TOTAL_SUPPLY / 3).into(), None);
+        assert_eq!(contract.total_minted().0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    fn test_mint() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        contract.mint(accounts(1), U128(500), Some("Staking pool funding".to_string()));
+        contract.mint(accounts(1), U128(250), None);
+
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 750);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 750);
+        assert_eq!(contract.total_minted().0, TOTAL_SUPPLY + 750);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a minter can mint")]
+    fn test_mint_not_owner() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.mint(accounts(1), U128(500), None);
+    }
+
+    #[test]
+    fn test_mint_by_minter() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        contract.add_minter(accounts(3));
+        assert!(contract.is_minter(accounts(3)));
+        assert_eq!(contract.get_minters(), vec![accounts(3)]);
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.mint(accounts(1), U128(500), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 500);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 500);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a minter can mint")]
+    fn test_mint_by_removed_minter() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        contract.add_minter(accounts(3));
+        contract.remove_minter(accounts(3));
+        assert!(!contract.is_minter(accounts(3)));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.mint(accounts(1), U128(500), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_add_minter_not_owner() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.add_minter(accounts(3));
+    }
+
+    #[test]
+    fn test_burn() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        testing_env!(context.attached_deposit(1).build());
+        let burn_amount = TOTAL_SUPPLY / 4;
+        contract.burn(burn_amount.into());
+
+        testing_env!(context.is_view(true).attached_deposit(0).build());
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, TOTAL_SUPPLY - burn_amount);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY - burn_amount);
+        assert_eq!(contract.total_minted().0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    #[should_panic(expected = "The account doesn't have enough balance")]
+    fn test_burn_more_than_balance() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        testing_env!(context.attached_deposit(1).build());
+        contract.burn((TOTAL_SUPPLY + 1).into());
+    }
+
+    fn capped_contract(max_supply: Balance) -> Contract {
+        Contract::new(
+            accounts(2),
+            TOTAL_SUPPLY.into(),
+            FungibleTokenMetadata {
+                spec: FT_METADATA_SPEC.to_string(),
+                name: "Capped token".to_string(),
+                symbol: "CAP".to_string(),
+                icon: None,
+                reference: None,
+                reference_hash: None,
+                decimals: 18,
+            },
+            Some(max_supply.into()),
+        )
+    }
+
+    #[test]
+    fn test_mint_under_max_supply() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = capped_contract(TOTAL_SUPPLY + 1_000);
+        contract.mint(accounts(1), U128(400), None);
+        // Reaching the cap exactly is allowed.
+        contract.mint(accounts(1), U128(600), None);
+
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.ft_max_supply(), Some(U128(TOTAL_SUPPLY + 1_000)));
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Minting would exceed max supply")]
+    fn test_mint_over_max_supply() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = capped_contract(TOTAL_SUPPLY + 1_000);
+        contract.mint(accounts(1), U128(1_001), None);
+    }
+
+    #[test]
+    fn test_mint_many() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = capped_contract(TOTAL_SUPPLY + 60);
+        testing_env!(context.build());
+        contract.mint_many(vec![
+            (accounts(1), U128(10)),
+            (accounts(3), U128(20)),
+            (accounts(4), U128(30)),
+        ]);
+
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 20);
+        assert_eq!(contract.ft_balance_of(accounts(4)).0, 30);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 60);
+        assert_eq!(contract.total_minted().0, TOTAL_SUPPLY + 60);
+        let logs = test_utils::get_logs();
+        assert_eq!(logs.len(), 1);
+        assert!(logs[0].contains(r#""event":"ft_mint""#));
+        for account_id in [accounts(1), accounts(3), accounts(4)].iter() {
+            assert!(logs[0].contains(&format!(r#""owner_id":"{}""#, account_id)));
+        }
+    }
+
+    #[test]
+    fn test_mint_many_over_max_supply() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = capped_contract(TOTAL_SUPPLY + 50);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            contract.mint_many(vec![(accounts(1), U128(10)), (accounts(3), U128(50))]);
+        }));
+        assert!(result.is_err());
+
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 0);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    fn test_no_max_supply() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        assert_eq!(contract.ft_max_supply(), None);
+    }
+
+    #[test]
+    fn test_transfer_registration_cost() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        testing_env!(context.is_view(true).build());
+        assert_eq!(contract.transfer_registration_cost(accounts(2)).0, 0);
+        assert_eq!(
+            contract.transfer_registration_cost(accounts(1)),
+            contract.storage_balance_bounds().min
+        );
+        assert!(contract.transfer_registration_cost(accounts(1)).0 > 0);
+    }
+
+    #[test]
+    fn test_update_metadata() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        let mut metadata = contract.ft_metadata();
+        metadata.name = "Staking Reward Token".to_string();
+        metadata.icon = Some("data:image/svg+xml,%3Csvg%3E%3C/svg%3E".to_string());
+        contract.update_metadata(metadata);
+
+        testing_env!(context.is_view(true).build());
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.name, "Staking Reward Token");
+        assert_eq!(metadata.symbol, "IKT");
+        assert_eq!(metadata.icon.as_deref(), Some("data:image/svg+xml,%3Csvg%3E%3C/svg%3E"));
+        assert_eq!(metadata.decimals, 18);
+    }
+
+    #[test]
+    fn test_set_icon() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        let old = contract.ft_metadata();
+        contract.set_icon(Some("data:image/svg+xml,%3Csvg%3E%3C/svg%3E".to_string()));
+
+        testing_env!(context.is_view(true).build());
+        let metadata = contract.ft_metadata();
+        assert_eq!(metadata.icon.as_deref(), Some("data:image/svg+xml,%3Csvg%3E%3C/svg%3E"));
+        assert_eq!(metadata.spec, old.spec);
+        assert_eq!(metadata.name, old.name);
+        assert_eq!(metadata.symbol, old.symbol);
+        assert_eq!(metadata.reference, old.reference);
+        assert_eq!(metadata.reference_hash, old.reference_hash);
+        assert_eq!(metadata.decimals, old.decimals);
+    }
+
+    #[test]
+    #[should_panic(expected = "Icon can't exceed 8192 bytes")]
+    fn test_set_icon_too_large() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        contract.set_icon(Some("a".repeat(MAX_ICON_BYTES + 1)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_set_icon_not_owner() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.set_icon(None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Decimals can't be changed")]
+    fn test_update_metadata_decimals() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        let mut metadata = contract.ft_metadata();
+        metadata.decimals = 8;
+        contract.update_metadata(metadata);
+    }
+
+    fn setup_paused() -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(0)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_paused(true);
+        testing_env!(context.storage_usage(env::storage_usage()).attached_deposit(1).build());
+        (context, contract)
+    }
+
+    #[test]
+    #[should_panic(expected = "Transfers are paused")]
+    fn test_transfer_while_paused() {
+        let (_context, mut contract) = setup_paused();
+        assert!(contract.is_paused());
+        contract.ft_transfer(accounts(1), U128(10), None);
+    }
+
+    #[test]
+    fn test_transfer_after_unpause() {
+        let (_context, mut contract) = setup_paused();
+        // Supply management keeps working while paused.
+        contract.mint(accounts(1), U128(5), None);
+        contract.set_paused(false);
+        contract.ft_transfer(accounts(1), U128(10), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 15);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_set_paused_not_owner() {
+        let (mut context, mut contract) = setup_paused();
+        testing_env!(context.attached_deposit(0).predecessor_account_id(accounts(1)).build());
+        contract.set_paused(false);
+    }
+
+    const SECOND: u64 = 1_000_000_000;
+
+    fn setup_vested() -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.block_timestamp(1_000 * SECOND).build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        // 1000 tokens over 300 seconds with a 100 second cliff.
+        contract.mint_vested(accounts(1), U128(1_000), 100, 300);
+        // The grant is minted right away, but stays out of the balance until it is claimed.
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 1_000);
+        assert_eq!(contract.total_minted().0, TOTAL_SUPPLY + 1_000);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert!(test_utils::get_logs().last().unwrap().contains(r#""event":"ft_mint""#));
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        (context, contract)
+    }
+
+    #[test]
+    #[should_panic(expected = "No vested tokens to claim")]
+    fn test_claim_vested_before_cliff() {
+        let (mut context, mut contract) = setup_vested();
+        testing_env!(context.block_timestamp(1_099 * SECOND).build());
+        assert_eq!(contract.vested_available(accounts(1)).0, 0);
+        contract.claim_vested();
+    }
+
+    #[test]
+    fn test_claim_vested() {
+        let (mut context, mut contract) = setup_vested();
+        // Past the cliff, everything unlocked since the start is claimable.
+        testing_env!(context.block_timestamp(1_100 * SECOND).build());
+        assert_eq!(contract.vested_available(accounts(1)).0, 333);
+        assert_eq!(contract.claim_vested().0, 333);
+
+        testing_env!(context.block_timestamp(1_200 * SECOND).build());
+        assert_eq!(contract.vested_available(accounts(1)).0, 333);
+        assert_eq!(contract.claim_vested().0, 333);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 666);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 1_000);
+        // Claims only move tokens that were already minted.
+        assert_eq!(
+            test_utils::get_logs(),
+            vec![format!("Released 333 vested tokens to @{}", accounts(1))]
+        );
+
+        // The final claim releases the rounding remainder.
+        testing_env!(context.block_timestamp(1_500 * SECOND).build());
+        assert_eq!(contract.vested_available(accounts(1)).0, 334);
+        assert_eq!(contract.claim_vested().0, 334);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 1_000);
+        assert_eq!(contract.vested_available(accounts(1)).0, 0);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY + 1_000);
+        assert_eq!(contract.total_minted().0, TOTAL_SUPPLY + 1_000);
+    }
+
+    #[test]
+    #[should_panic(expected = "Minting would exceed max supply")]
+    fn test_mint_over_max_supply_with_vesting() {
+        let context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = capped_contract(TOTAL_SUPPLY + 1_000);
+        contract.mint_vested(accounts(1), U128(600), 0, 300);
+        // The unclaimed grant already counts against the cap.
+        contract.mint(accounts(3), U128(401), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Vesting amount overflow")]
+    fn test_vested_at_overflow() {
+        let schedule = VestingSchedule {
+            total: Balance::MAX,
+            claimed: 0,
+            start_sec: 0,
+            cliff_sec: 0,
+            duration_sec: 300,
+        };
+        schedule.vested_at(100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Can't unregister an account with a vesting schedule")]
+    fn test_storage_unregister_with_vesting() {
+        let (mut context, mut contract) = setup_vested();
+        testing_env!(context.attached_deposit(1).build());
+        contract.storage_unregister(Some(true));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner or a minter can mint")]
+    fn test_mint_vested_not_minter() {
+        let (_context, mut contract) = setup_vested();
+        contract.mint_vested(accounts(3), U128(1_000), 0, 300);
+    }
+
+    /// Registers `accounts(1)` with 100 tokens and freezes it.
+    fn setup_frozen() -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup_paused();
+        contract.set_paused(false);
+        contract.ft_transfer(accounts(1), U128(100), None);
+        testing_env!(context.attached_deposit(0).build());
+        contract.freeze_account(accounts(1));
+        testing_env!(context.attached_deposit(1).build());
+        (context, contract)
+    }
+
+    #[test]
+    #[should_panic(expected = "Account is frozen")]
+    fn test_transfer_from_frozen_account() {
+        let (mut context, mut contract) = setup_frozen();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_transfer(accounts(2), U128(10), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account is frozen")]
+    fn test_transfer_to_frozen_account() {
+        let (_context, mut contract) = setup_frozen();
+        contract.ft_transfer(accounts(1), U128(10), None);
+    }
+
+    #[test]
+    fn test_unfreeze_account() {
+        let (mut context, mut contract) = setup_frozen();
+        assert!(contract.is_frozen(accounts(1)));
+        // Balances stay visible and the owner can still mint to the frozen account.
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 100);
+        contract.mint(accounts(1), U128(5), None);
+
+        testing_env!(context.attached_deposit(0).build());
+        contract.unfreeze_account(accounts(1));
+        assert!(!contract.is_frozen(accounts(1)));
+        testing_env!(context.attached_deposit(1).build());
+        contract.ft_transfer(accounts(1), U128(10), None);
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.ft_transfer(accounts(2), U128(15), None);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 100);
+    }
+
+    #[test]
+    #[should_panic(expected = "Account is frozen")]
+    fn test_burn_frozen_account() {
+        let (mut context, mut contract) = setup_frozen();
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.burn(U128(10));
+    }
+
+    #[test]
+    fn test_burn_from_frozen() {
+        let (_context, mut contract) = setup_frozen();
+        contract.burn_from_frozen(accounts(1), U128(40));
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 60);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY - 40);
+        assert!(test_utils::get_logs().iter().any(|log| log.contains(r#""event":"ft_burn""#)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Account is not frozen")]
+    fn test_burn_from_frozen_not_frozen() {
+        let (_context, mut contract) = setup_frozen();
+        contract.burn_from_frozen(accounts(3), U128(10));
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the owner can call this method")]
+    fn test_burn_from_frozen_not_owner() {
+        let (mut context, mut contract) = setup_frozen();
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.burn_from_frozen(accounts(1), U128(10));
+    }
+
+    #[test]
+    fn test_ownership_transfer() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        contract.propose_owner(accounts(3));
+        assert_eq!(contract.get_owner(), accounts(2));
+        assert_eq!(contract.get_pending_owner(), Some(accounts(3)));
+
+        testing_env!(context.predecessor_account_id(accounts(3)).build());
+        contract.accept_ownership();
+        assert_eq!(contract.get_owner(), accounts(3));
+        assert_eq!(contract.get_pending_owner(), None);
+        // The new owner can use owner-only methods.
+        contract.set_paused(true);
+        assert!(contract.is_paused());
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the pending owner can accept ownership")]
+    fn test_accept_ownership_not_pending_owner() {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        contract.propose_owner(accounts(3));
+
+        testing_env!(context.predecessor_account_id(accounts(1)).build());
+        contract.accept_ownership();
+    }
+
+    /// Sends 100 tokens from `sender` to `accounts(1)` with `ft_transfer_call`, after funding the
+    /// sender with 1000 tokens if it isn't the owner.
+    fn setup_transfer_call(sender: AccountId) -> (VMContextBuilder, Contract) {
+        let (mut context, mut contract) = setup_transfer_many();
+        if sender != accounts(0) {
+            contract.ft_transfer(sender.clone(), U128(1_000), None);
+        }
+        testing_env!(context.predecessor_account_id(sender).attached_deposit(1).build());
+        contract.ft_transfer_call(accounts(1), U128(100), None, String::new());
+        (context, contract)
+    }
+
+    fn resolve_transfer_result(unused_amount: Balance) -> Vec<PromiseResult> {
+        vec![PromiseResult::Successful(near_sdk::serde_json::to_vec(&U128(unused_amount)).unwrap())]
+    }
+
+    #[test]
+    fn test_resolve_transfer_refund() {
+        let (mut context, mut contract) = setup_transfer_call(accounts(0));
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            resolve_transfer_result(30),
+        );
+        let used = contract.ft_resolve_transfer(accounts(0), accounts(1), U128(100));
+        assert_eq!(used.0, 70);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY - 70);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 70);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
+    }
+
+    #[test]
+    fn test_resolve_transfer_receiver_unregistered() {
+        let (mut context, mut contract) = setup_transfer_call(accounts(0));
+        // The receiver force-closes its account before the transfer resolves, burning the 100.
+        testing_env!(context.predecessor_account_id(accounts(1)).attached_deposit(1).build());
+        assert!(contract.storage_unregister(Some(true)));
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            resolve_transfer_result(30),
+        );
+        let used = contract.ft_resolve_transfer(accounts(0), accounts(1), U128(100));
+        assert_eq!(used.0, 100);
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY - 100);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY - 100);
+    }
+
+    #[test]
+    fn test_resolve_transfer_sender_unregistered() {
+        let (mut context, mut contract) = setup_transfer_call(accounts(2));
+        // The sender force-closes its account, burning its remaining 900.
+        testing_env!(context.attached_deposit(1).build());
+        assert!(contract.storage_unregister(Some(true)));
+
+        testing_env!(
+            context.predecessor_account_id(accounts(0)).attached_deposit(0).build(),
+            Default::default(),
+            Default::default(),
+            Default::default(),
+            vec![PromiseResult::Failed],
+        );
+        let used = contract.ft_resolve_transfer(accounts(2), accounts(1), U128(100));
+        assert_eq!(used.0, 100);
+        // The refund can't reach the sender, so it is burned instead of lost.
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY - 1_000);
+        assert!(test_utils::get_logs().iter().any(|log| log.contains(r#""event":"ft_burn""#)));
+    }
+
+    fn setup_transfer_many() -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(0));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(0).into(), TOTAL_SUPPLY.into());
+        for i in 1..4 {
+            testing_env!(context
+                .storage_usage(env::storage_usage())
+                .attached_deposit(contract.storage_balance_bounds().min.into())
+                .predecessor_account_id(accounts(i))
+                .build());
+            contract.storage_deposit(None, None);
+        }
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(0))
+            .build());
+        (context, contract)
+    }
+
+    #[test]
+    fn test_transfer_many() {
+        let (_context, mut contract) = setup_transfer_many();
+        contract.ft_transfer_many(
+            vec![(accounts(1), U128(10)), (accounts(2), U128(20)), (accounts(3), U128(30))],
+            Some("Rewards".to_string()),
+        );
+
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY - 60);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 10);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 20);
+        assert_eq!(contract.ft_balance_of(accounts(3)).0, 30);
+        assert_eq!(contract.ft_total_supply().0, TOTAL_SUPPLY);
+        assert_eq!(test_utils::get_logs().len(), 1);
+    }
+
+    #[test]
+    fn test_transfer_many_over_budget() {
+        let (_context, mut contract) = setup_transfer_many();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            contract.ft_transfer_many(
+                vec![(accounts(1), U128(10)), (accounts(2), U128(TOTAL_SUPPLY))],
+                None,
+            );
+        }));
+        assert!(result.is_err());
+
+        assert_eq!(contract.ft_balance_of(accounts(0)).0, TOTAL_SUPPLY);
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 0);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "Transfers can't be empty")]
+    fn test_transfer_many_empty() {
+        let (_context, mut contract) = setup_transfer_many();
+        contract.ft_transfer_many(vec![], None);
+    }
+
+    #[test]
+    fn test_ft_accounts_pagination() {
+        let (mut context, mut contract) = setup_transfer_many();
+        for i in 1..4 {
+            contract.ft_transfer(accounts(i), U128(i as u128 * 10), None);
+        }
+
+        testing_env!(context.is_view(true).attached_deposit(0).build());
+        let first_page = contract.ft_accounts(None, Some(2));
+        let second_page = contract.ft_accounts(Some(2), Some(2));
+        assert_eq!(
+            first_page,
+            vec![(accounts(0), U128(TOTAL_SUPPLY - 60)), (accounts(1), U128(10))]
+        );
+        assert_eq!(second_page, vec![(accounts(2), U128(20)), (accounts(3), U128(30))]);
+        assert!(contract.ft_accounts(Some(4), None).is_empty());
+    }
+
+    fn setup_trusted_spender(cap: Option<U128>) -> (VMContextBuilder, Contract) {
+        let mut context = get_context(accounts(2));
+        testing_env!(context.build());
+        let mut contract = Contract::new_default_meta(accounts(2).into(), TOTAL_SUPPLY.into());
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(contract.storage_balance_bounds().min.into())
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.storage_deposit(None, None);
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(2))
+            .build());
+        contract.set_trusted_spender(accounts(3), cap);
+        (context, contract)
+    }
+
+    #[test]
+    fn test_transfer_from_within_cap() {
+        let (mut context, mut contract) = setup_trusted_spender(Some(U128(100)));
+        assert_eq!(contract.trusted_spender(accounts(2)), Some((accounts(3), U128(100))));
+
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.transfer_from(accounts(2), accounts(1), U128(60), None);
+        contract.transfer_from(accounts(2), accounts(1), U128(40), None);
+
+        assert_eq!(contract.ft_balance_of(accounts(1)).0, 100);
+        assert_eq!(contract.ft_balance_of(accounts(2)).0, TOTAL_SUPPLY - 100);
+        assert_eq!(contract.trusted_spender(accounts(2)), Some((accounts(3), U128(0))));
+    }
+
+    #[test]
+    #[should_panic(expected = "The amount exceeds the trusted spender cap")]
+    fn test_transfer_from_beyond_cap() {
+        let (mut context, mut contract) = setup_trusted_spender(Some(U128(100)));
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(3))
+            .build());
+        contract.transfer_from(accounts(2), accounts(1), U128(60), None);
+        contract.transfer_from(accounts(2), accounts(1), U128(41), None);
+    }
+
+    #[test]
+    #[should_panic(expected = "Only the trusted spender can transfer from this account")]
+    fn test_transfer_from_untrusted_caller() {
+        let (mut context, mut contract) = setup_trusted_spender(None);
+        testing_env!(context
+            .storage_usage(env::storage_usage())
+            .attached_deposit(1)
+            .predecessor_account_id(accounts(1))
+            .build());
+        contract.transfer_from(accounts(2), accounts(1), U128(1), None);
+    }
 }